@@ -1,11 +1,12 @@
 use futures::stream::{BoxStream, SplitStream};
 use prost::Message;
 use std::error::Error;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio_tungstenite::{
     connect_async,
     tungstenite::http::Request,
+    tungstenite::Message as WsMessage,
     MaybeTlsStream,
     WebSocketStream,
 };
@@ -17,12 +18,12 @@ use clickplanet_proto::clicks::*;
 use futures::StreamExt;
 use rand::Rng;
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::net::TcpStream;
 use tokio::time::sleep;
 use tokio_retry::strategy::{jitter, ExponentialBackoff};
-use tokio_retry::Retry;
+use tokio_retry::{Retry, RetryIf};
 use url::Url;
 
 pub trait TileCount {
@@ -48,6 +49,62 @@ impl Default for WebSocketConfig {
     }
 }
 
+/// Last notification a listener received, sent back on reconnection to get the missed ones replayed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResumePosition {
+    pub epoch: u32,
+    pub sequence: u64,
+}
+
+#[derive(Clone, Debug)]
+pub enum UpdateEvent {
+    Update(clicks::UpdateNotification),
+    /// Updates were missed and the server could not replay them: ownerships should be reloaded
+    Resync,
+    /// Every score, first then whenever deltas were missed. Only sent with `with_envelopes`.
+    LeaderboardSnapshot(clicks::LeaderboardResponse),
+    /// Countries whose score changed since the previous leaderboard event, 0 once they own no tile
+    LeaderboardDelta(clicks::LeaderboardDelta),
+}
+
+/// Server-side filter of the update stream. Tile and country criteria are combined, empty ones match everything.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(tag = "type", rename = "subscribe")]
+pub struct Subscription {
+    /// Inclusive `(start, end)` tile id ranges
+    pub tile_ranges: Vec<(u32, u32)>,
+    pub tile_ids: Vec<u32>,
+    pub countries: Vec<String>,
+    pub country_match: CountryMatch,
+}
+
+/// Which side of an ownership change has to be one of the subscribed countries.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CountryMatch {
+    Owner,
+    Previous,
+    #[default]
+    Either,
+}
+
+impl Subscription {
+    pub fn tiles(tile_ids: impl IntoIterator<Item = u32>) -> Self {
+        Self {
+            tile_ids: tile_ids.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn countries(countries: impl IntoIterator<Item = String>, country_match: CountryMatch) -> Self {
+        Self {
+            countries: countries.into_iter().collect(),
+            country_match,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct OwnershipResponse {
     data: String,  // base64 encoded protobuf
@@ -58,8 +115,43 @@ pub struct ClickPlanetRestClient {
     host: String,
     port: u16,
     secure: bool,
+    framing: UpdateFraming,
+    binary_transport: bool,
+}
+
+/// How the server sends updates over the WebSocket, negotiated with a subprotocol.
+#[derive(Clone, Copy, PartialEq)]
+enum UpdateFraming {
+    Notification,
+    Batch,
+    Envelope,
+}
+
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Body of an RPC response, in the transport the client asked for.
+enum RpcBody {
+    Json(serde_json::Value),
+    Protobuf(prost::bytes::Bytes),
+}
+
+impl RpcBody {
+    fn decode<T: Message + Default>(&self) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            RpcBody::Json(response_json) => decode_data_field(response_json),
+            RpcBody::Protobuf(bytes) => T::decode(bytes.clone())
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>),
+        }
+    }
 }
 
+/// Subprotocol asking the server for `UpdateBatch` frames, only understood by clickplanet-server.
+pub const BATCH_SUBPROTOCOL: &str = "clickplanet.batch.v1";
+
+/// Subprotocol asking the server for `Envelope` frames: update batches and leaderboard changes.
+pub const ENVELOPE_SUBPROTOCOL: &str = "clickplanet.envelope.v1";
+
 pub const CLIENT_NAME: &'static str = "clickplanet client owned by valdo404";
 
 impl ClickPlanetRestClient {
@@ -77,20 +169,88 @@ impl ClickPlanetRestClient {
             host: base_url.to_string(),
             port,
             secure: secure,
+            framing: UpdateFraming::Notification,
+            binary_transport: false,
+        }
+    }
+
+    /// Receive updates grouped in `UpdateBatch` frames. The server must support the batch subprotocol.
+    pub fn with_batched_updates(mut self) -> Self {
+        self.framing = UpdateFraming::Batch;
+        self
+    }
+
+    /// Receive updates in `Envelope` frames, along with the leaderboard as `UpdateEvent`s.
+    /// The server must support the envelope subprotocol.
+    pub fn with_envelopes(mut self) -> Self {
+        self.framing = UpdateFraming::Envelope;
+        self
+    }
+
+    /// Send and receive raw protobuf bodies on the RPC endpoints instead of the JSON wrapper.
+    /// The server must support `application/x-protobuf`.
+    pub fn with_binary_transport(mut self) -> Self {
+        self.binary_transport = true;
+        self
+    }
+
+    fn rpc_url(&self, path: &str) -> String {
+        format!("{}://{}:{}{}", if self.secure { "https" } else { "http" }, self.host, self.port, path)
+    }
+
+    fn with_client_headers(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        builder
+            .header("User-Agent", CLIENT_NAME)
+            .header("Origin", format!("https://{}", self.host))
+            .header("Referer", format!("https://{}/", self.host))
+    }
+
+    fn rpc_request<M: Message>(&self, client: &Client, path: &str, request: &M) -> reqwest::RequestBuilder {
+        let builder = self.with_client_headers(client.post(self.rpc_url(path)));
+
+        if self.binary_transport {
+            builder
+                .header("Content-Type", PROTOBUF_CONTENT_TYPE)
+                .header("Accept", PROTOBUF_CONTENT_TYPE)
+                .body(request.encode_to_vec())
+        } else {
+            builder
+                .header("Content-Type", "application/json")
+                .json(&json!({
+                    "data": request.encode_to_vec(),
+                }))
+        }
+    }
+
+    async fn read_rpc_body(&self, response: reqwest::Response) -> Result<RpcBody, reqwest::Error> {
+        if self.binary_transport {
+            Ok(RpcBody::Protobuf(response.bytes().await?))
+        } else {
+            Ok(RpcBody::Json(response.json::<serde_json::Value>().await?))
         }
     }
 
 
     pub async fn click_tile(&self, tile_id: u32, country_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.send_click(tile_id, country_id, false).await.map(|_| ())
+    }
+
+    /// Clicks and waits until the server has applied the click, the response carries its `ClickOutcome`.
+    pub async fn click_tile_and_wait(&self, tile_id: u32, country_id: &str) -> Result<clicks::ClickResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send_click(tile_id, country_id, true).await?;
+
+        response.decode()
+    }
+
+    async fn send_click(&self, tile_id: u32, country_id: &str, wait_for_apply: bool) -> Result<RpcBody, Box<dyn std::error::Error + Send + Sync>> {
         let request = clicks::ClickRequest {
             tile_id: tile_id.try_into().unwrap(),
             country_id: country_id.to_string(),
+            wait_for_apply,
         };
 
-        let mut proto_bytes = Vec::new();
-        request.encode(&mut proto_bytes)?;
-
-        let base_url = self.host.clone();
+        // The same key on every attempt, so the server applies the click once and answers retries with its response
+        let idempotency_key = generate_idempotency_key();
 
         // Configure the retry strategy
         let retry_strategy = ExponentialBackoff::from_millis(100)
@@ -100,24 +260,21 @@ impl ClickPlanetRestClient {
 
         let client = self.client.clone();
 
-        let result = Retry::spawn(retry_strategy, || async {
-            let response = client
-                .post(format!("{}://{}:{}/v2/rpc/click", if self.secure { "https" } else { "http" }, base_url, self.port))
-                .header("User-Agent", CLIENT_NAME)
-                .header("Content-Type", "application/json")
-                .header("Origin", format!("https://{}", base_url))
-                .header("Referer", format!("https://{}/", base_url))
-                .json(&json!({
-                "data": proto_bytes,
-            }))
+        // Other client errors would be answered the same on every attempt
+        let retryable = |e: &reqwest::Error| e.status()
+            .is_none_or(|status| !status.is_client_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS);
+
+        let result = RetryIf::spawn(retry_strategy, || async {
+            let response = self.rpc_request(&client, "/v2/rpc/click", &request)
+                .header(IDEMPOTENCY_KEY_HEADER, &idempotency_key)
                 .send()
                 .await?;
 
-            response.error_for_status().map(|_| ())
-        }).await;
+            self.read_rpc_body(response.error_for_status()?).await
+        }, retryable).await;
 
         match result {
-            Ok(_) => Ok(()),
+            Ok(response) => Ok(response),
             Err(e) => Err(Box::new(e)),
         }
     }
@@ -134,48 +291,68 @@ impl ClickPlanetRestClient {
             end_tile_id: end_tile_id.try_into().unwrap(),
         };
 
-        let mut proto_bytes = Vec::new();
-        batch_request.encode(&mut proto_bytes)
-            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
-            ?;
-
-        let payload = json!({
-            "data": proto_bytes,
-        });
-
-        let response = client
-            .post(format!("{}://{}:{}/v2/rpc/ownerships-by-batch", if self.secure { "https" } else { "http" }, self.host, self.port))
-            .header("User-Agent", CLIENT_NAME)
-            .header("Content-Type", "application/json")
-            .header("Origin", format!("https://{}", self.host))
-            .header("Referer", format!("https://{}/", self.host))
-            .json(&payload)
+        let response = self.rpc_request(&client, "/v2/rpc/ownerships-by-batch", &batch_request)
             .send()
             .await
             .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
 
-        let response_json: serde_json::Value = response.json().await
+        let body = self.read_rpc_body(response).await
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+
+        body.decode()
+    }
+
+    /// Tiles changed after `since_sequence`. Call again with the returned cursor and epoch while `has_more` is set,
+    /// and with 0 and 0 for every owned tile.
+    pub async fn get_ownerships_since(
+        &self,
+        since_sequence: u64,
+        epoch: u32,
+    ) -> Result<clicks::OwnershipsSinceResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let since_request = clicks::OwnershipsSinceRequest {
+            since_sequence,
+            limit: 0,
+            epoch,
+        };
+
+        let response = self.rpc_request(&self.client, "/v2/rpc/ownerships-since", &since_request)
+            .send()
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?
+            .error_for_status()
             .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
 
-        let str_result = response_json["data"]
-            .as_str()
-            .ok_or_else(|| std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid or missing data field in response"
-            ))
+        let body = self.read_rpc_body(response).await
             .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
 
-        let result: Result<Vec<u8>, DecodeError> = STANDARD.decode(
-            str_result
-        );
+        body.decode()
+    }
 
-        let proto_bytes: Vec<u8> = result.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+    /// The whole map in one request, sent as a `CompactOwnershipState`. The ownerships are in tile id order
+    /// and their timestamps are 0 unless `with_timestamps` is set.
+    pub async fn get_compact_ownerships(
+        &self,
+        with_timestamps: bool,
+    ) -> Result<clicks::OwnershipState, Box<dyn std::error::Error + Send + Sync>> {
+        let mut request = self.with_client_headers(self.client.get(self.rpc_url("/v2/rpc/ownerships-compact")))
+            .query(&[("timestamps", with_timestamps)]);
+        if self.binary_transport {
+            request = request.header("Accept", PROTOBUF_CONTENT_TYPE);
+        }
 
-        let ownership_state = clicks::OwnershipState::decode(&proto_bytes[..])
+        let response = request
+            .send()
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?
+            .error_for_status()
             .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
 
+        let body = self.read_rpc_body(response).await
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
 
-        Ok(ownership_state)
+        let compact: clicks::CompactOwnershipState = body.decode()?;
+        compact.to_state()
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
     }
 
     pub async fn get_ownerships(
@@ -234,6 +411,12 @@ impl ClickPlanetRestClient {
     }
 
     pub async fn connect_websocket(&self) -> Result<SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.connect_websocket_from(None, None).await
+    }
+
+    /// Connects the update stream, asking the server to replay what came after `resume` if given
+    /// and to only send the updates matching `subscription`.
+    pub async fn connect_websocket_from(&self, resume: Option<ResumePosition>, subscription: Option<&Subscription>) -> Result<SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>, Box<dyn std::error::Error + Send + Sync>> {
         let config = WebSocketConfig::default();
 
         let retry_strategy = ExponentialBackoff::from_millis(config.initial_interval.as_millis() as u64)
@@ -242,10 +425,18 @@ impl ClickPlanetRestClient {
 
         let result = Retry::spawn(retry_strategy, || async {
             let ws_url = format!("{}://{}:{}/v2/ws/listen", if self.secure { "wss" } else { "ws" }, self.host, self.port);
+            let mut url = Url::parse(&ws_url).map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+            if let Some(position) = resume {
+                url.query_pairs_mut()
+                    .append_pair("epoch", &position.epoch.to_string())
+                    .append_pair("last_sequence", &position.sequence.to_string());
+            }
+            // In the upgrade request, so that the replay is filtered too
+            if let Some(subscription) = subscription {
+                url.query_pairs_mut().append_pair("subscribe", &serde_json::to_string(subscription)?);
+            }
 
-            let url = Url::parse(&ws_url).map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-
-            let request = Request::builder()
+            let mut request = Request::builder()
                 .uri(url.as_str())
                 .header("User-Agent", CLIENT_NAME)
                 .header("Origin", format!("{}://{}:{}", if self.secure { "https" } else { "http" }, self.host, self.port))
@@ -253,8 +444,15 @@ impl ClickPlanetRestClient {
                 .header("Connection", "Upgrade")
                 .header("Upgrade", "websocket")
                 .header("Sec-WebSocket-Version", "13")
-                .header("Sec-WebSocket-Key", generate_websocket_key())
-                .body(())?;
+                .header("Sec-WebSocket-Key", generate_websocket_key());
+
+            match self.framing {
+                UpdateFraming::Notification => {}
+                UpdateFraming::Batch => request = request.header("Sec-WebSocket-Protocol", BATCH_SUBPROTOCOL),
+                UpdateFraming::Envelope => request = request.header("Sec-WebSocket-Protocol", ENVELOPE_SUBPROTOCOL),
+            }
+
+            let request = request.body(())?;
 
             println!("Attempting WebSocket connection...");
             let (ws_stream, _) = connect_async(request).await
@@ -273,12 +471,43 @@ impl ClickPlanetRestClient {
     }
 
     pub async fn listen_for_updates(&self) -> Result<BoxStream<'_, clicks::UpdateNotification>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let stream = Box::pin(futures::stream::unfold((), move |_| {
+        self.listen_for_updates_matching(None).await
+    }
+
+    /// Updates filtered by the server, replayed ones included.
+    pub async fn listen_for_updates_matching(&self, subscription: Option<Subscription>) -> Result<BoxStream<'_, clicks::UpdateNotification>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let events = self.listen_for_events_matching(subscription).await?;
+
+        let stream = events.filter_map(|event| async move {
+            match event {
+                UpdateEvent::Update(notification) => Some(notification),
+                UpdateEvent::Resync => {
+                    eprintln!("Missed updates could not be replayed, ownerships should be reloaded");
+                    None
+                }
+                UpdateEvent::LeaderboardSnapshot(_) | UpdateEvent::LeaderboardDelta(_) => None,
+            }
+        });
+
+        Ok(stream.boxed())
+    }
+
+    /// Like `listen_for_updates`, but also reports when missed updates could not be replayed after a reconnection.
+    pub async fn listen_for_events(&self) -> Result<BoxStream<'_, UpdateEvent>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        self.listen_for_events_matching(None).await
+    }
+
+    pub async fn listen_for_events_matching(&self, subscription: Option<Subscription>) -> Result<BoxStream<'_, UpdateEvent>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let subscription = Arc::new(subscription);
+        let resume: Arc<Mutex<Option<ResumePosition>>> = Arc::new(Mutex::new(None));
+
+        let stream = Box::pin(futures::stream::unfold(resume, move |resume| {
             let client = self;  // No need to clone here
+            let subscription = subscription.clone();
             async move {
                 loop {
-                    match Self::create_update_stream(client).await {
-                        Ok(stream) => return Some((stream, ())),
+                    match Self::create_update_stream(client, resume.clone(), subscription.clone()).await {
+                        Ok(stream) => return Some((stream, resume)),
                         Err(e) => {
                             eprintln!("Error in WebSocket connection: {}. Retrying...", e);
                             tokio::time::sleep(Duration::from_secs(1)).await;
@@ -292,41 +521,126 @@ impl ClickPlanetRestClient {
         Ok(stream.boxed())
     }
 
-    async fn create_update_stream<'a>(client: &'a ClickPlanetRestClient) -> Result<BoxStream<'a, clicks::UpdateNotification>, Box<dyn std::error::Error + Send + Sync + 'a>> {
-        // Rest of the implementation remains the same
+    async fn create_update_stream<'a>(client: &'a ClickPlanetRestClient, resume: Arc<Mutex<Option<ResumePosition>>>, subscription: Arc<Option<Subscription>>) -> Result<BoxStream<'a, UpdateEvent>, Box<dyn std::error::Error + Send + Sync + 'a>> {
         let config = WebSocketConfig::default();
         let retry_strategy = ExponentialBackoff::from_millis(config.initial_interval.as_millis() as u64)
             .max_delay(config.max_interval)
             .map(jitter);
 
+        let position = *resume.lock().unwrap();
+
         let read = Retry::spawn(retry_strategy, || async {
-            client.connect_websocket().await
+            client.connect_websocket_from(position, subscription.as_ref().as_ref()).await
         }).await?;
 
+        let framing = client.framing;
         let stream = read
-            .filter_map(|message| async move {
-                match message {
-                    Ok(msg) => {
-                        match clicks::UpdateNotification::decode(msg.into_data().as_slice()) {
-                            Ok(notification) => Some(notification),
-                            Err(e) => {
-                                eprintln!("Error decoding message: {}", e);
-                                None
+            .filter_map(move |message| {
+                let resume = resume.clone();
+                async move {
+                    match message {
+                        Ok(WsMessage::Binary(data)) => {
+                            match decode_frame(framing, &data) {
+                                Ok(events) => {
+                                    {
+                                        let mut position = resume.lock().unwrap();
+                                        for event in &events {
+                                            if let UpdateEvent::Update(notification) = event {
+                                                advance_resume_position(&mut position, notification);
+                                            }
+                                        }
+                                    }
+                                    Some(events)
+                                }
+                                Err(e) => {
+                                    eprintln!("Error decoding message: {}", e);
+                                    None
+                                }
                             }
                         }
-                    },
-                    Err(e) => {
-                        eprintln!("WebSocket message error: {}", e);
-                        None
+                        Ok(WsMessage::Text(text)) => {
+                            let control: serde_json::Value = serde_json::from_str(&text).ok()?;
+                            match control["type"].as_str() {
+                                Some("resync") => Some(vec![UpdateEvent::Resync]),
+                                _ => None,
+                            }
+                        }
+                        Ok(WsMessage::Close(Some(frame))) => {
+                            eprintln!("WebSocket closed by the server: {} {}", frame.code, frame.reason);
+                            None
+                        }
+                        Ok(_) => None,
+                        Err(e) => {
+                            eprintln!("WebSocket message error: {}", e);
+                            None
+                        }
                     }
                 }
             })
+            .flat_map(futures::stream::iter)
             .boxed();
 
         Ok(stream)
     }
 }
 
+fn decode_frame(framing: UpdateFraming, data: &[u8]) -> Result<Vec<UpdateEvent>, prost::DecodeError> {
+    match framing {
+        UpdateFraming::Notification => clicks::UpdateNotification::decode(data)
+            .map(|notification| vec![UpdateEvent::Update(notification)]),
+        UpdateFraming::Batch => clicks::UpdateBatch::decode(data)
+            .map(|batch| batch.updates.into_iter().map(UpdateEvent::Update).collect()),
+        UpdateFraming::Envelope => clicks::Envelope::decode(data).map(|envelope| match envelope.payload {
+            Some(clicks::envelope::Payload::Updates(batch)) => batch.updates.into_iter().map(UpdateEvent::Update).collect(),
+            Some(clicks::envelope::Payload::LeaderboardSnapshot(leaderboard)) => vec![UpdateEvent::LeaderboardSnapshot(leaderboard)],
+            Some(clicks::envelope::Payload::LeaderboardDelta(delta)) => vec![UpdateEvent::LeaderboardDelta(delta)],
+            // A payload added after this client was built
+            None => Vec::new(),
+        }),
+    }
+}
+
+// Coalesced updates of a slow connection may arrive out of sequence order
+fn advance_resume_position(position: &mut Option<ResumePosition>, notification: &clicks::UpdateNotification) {
+    let behind = match position {
+        Some(current) => current.epoch != notification.epoch || current.sequence < notification.sequence,
+        None => true,
+    };
+
+    if notification.sequence > 0 && behind {
+        *position = Some(ResumePosition {
+            epoch: notification.epoch,
+            sequence: notification.sequence,
+        });
+    }
+}
+
+/// Decodes the `{"data": "<base64 protobuf>"}` envelope used by the RPC endpoints.
+fn decode_data_field<T: Message + Default>(response_json: &serde_json::Value) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    let str_result = response_json["data"]
+        .as_str()
+        .ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid or missing data field in response"
+        ))
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+
+    let result: Result<Vec<u8>, DecodeError> = STANDARD.decode(
+        str_result
+    );
+
+    let proto_bytes: Vec<u8> = result.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
+
+    T::decode(&proto_bytes[..])
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+}
+
+/// Identifies one logical click across its retries
+fn generate_idempotency_key() -> String {
+    let key: [u8; 16] = rand::thread_rng().gen();
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 pub fn generate_websocket_key() -> String {
     use base64::{engine::general_purpose::STANDARD, Engine as _};
     use rand::Rng;
@@ -3,6 +3,7 @@ mod client;
 
 pub use client::ClickPlanetRestClient;
 pub use client::TileCount;
+pub use client::{CountryMatch, ResumePosition, Subscription, UpdateEvent};
 
 pub mod prelude {
     pub use super::ClickPlanetRestClient;
@@ -1,4 +1,5 @@
 fn main() {
-    prost_build::compile_protos(&["proto/clicks.proto"], &["proto/"])
+    tonic_build::configure()
+        .compile_protos(&["proto/clicks.proto"], &["proto/"])
         .unwrap();
 }
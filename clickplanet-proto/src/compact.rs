@@ -0,0 +1,253 @@
+use std::collections::BTreeSet;
+
+use thiserror::Error;
+
+use crate::clicks::{CompactOwnershipState, Ownership, OwnershipState};
+
+/// Well above the tiles of the planet, keeps a malformed state from allocating billions of ownerships
+pub const MAX_OWNED_TILES: usize = 1 << 22;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum CompactOwnershipError {
+    #[error("run_owners and run_lengths differ in length ({owners} and {lengths})")]
+    RunCountMismatch { owners: usize, lengths: usize },
+    #[error("owner index {0} is beyond the country palette")]
+    UnknownOwner(u32),
+    #[error("{offsets} timestamps for {tiles} owned tiles")]
+    TimestampCountMismatch { offsets: usize, tiles: usize },
+    #[error("runs go beyond the last tile id")]
+    TileIdOverflow,
+    #[error("runs own more than {MAX_OWNED_TILES} tiles")]
+    TooManyTiles,
+    #[error("timestamps go beyond the last representable one")]
+    TimestampOverflow,
+    #[error("{indexes} node indexes for {tiles} owned tiles")]
+    NodeCountMismatch { indexes: usize, tiles: usize },
+    #[error("node index {0} is beyond the node palette")]
+    UnknownNode(u32),
+}
+
+impl CompactOwnershipState {
+    /// Timestamps make up most of the encoded size, leave them out unless the caller needs them
+    pub fn from_state(state: &OwnershipState, with_timestamps: bool) -> Self {
+        let countries: Vec<String> = state.ownerships.iter()
+            .map(|ownership| ownership.country_id.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        let mut ownerships: Vec<&Ownership> = state.ownerships.iter().collect();
+        ownerships.sort_by_key(|ownership| ownership.tile_id);
+        ownerships.dedup_by_key(|ownership| ownership.tile_id);
+
+        let mut compact = CompactOwnershipState {
+            first_tile_id: ownerships.first().map(|ownership| ownership.tile_id).unwrap_or(0),
+            base_timestamp_ns: if with_timestamps {
+                ownerships.iter().map(|ownership| ownership.timestamp_ns).min().unwrap_or(0)
+            } else {
+                0
+            },
+            nodes: if with_timestamps {
+                ownerships.iter().map(|ownership| ownership.node_id).collect::<BTreeSet<_>>().into_iter().collect()
+            } else {
+                Vec::new()
+            },
+            ..Default::default()
+        };
+
+        let mut next_tile_id = compact.first_tile_id;
+        for ownership in ownerships {
+            if ownership.tile_id > next_tile_id {
+                compact.push_run(0, ownership.tile_id - next_tile_id);
+            }
+
+            // Present in the palette built from these same ownerships
+            let owner = countries.binary_search(&ownership.country_id).unwrap() as u32 + 1;
+            compact.push_run(owner, 1);
+
+            if with_timestamps {
+                compact.timestamp_offsets_ns.push(ownership.timestamp_ns - compact.base_timestamp_ns);
+                compact.node_indexes.push(compact.nodes.binary_search(&ownership.node_id).unwrap() as u32);
+            }
+            next_tile_id = ownership.tile_id.saturating_add(1);
+        }
+
+        compact.countries = countries;
+        compact
+    }
+
+    fn push_run(&mut self, owner: u32, length: u32) {
+        match (self.run_owners.last(), self.run_lengths.last_mut()) {
+            (Some(last_owner), Some(last_length)) if *last_owner == owner => *last_length += length,
+            _ => {
+                self.run_owners.push(owner);
+                self.run_lengths.push(length);
+            }
+        }
+    }
+
+    /// Owned tiles in tile id order, with a timestamp and node of 0 when timestamps were left out
+    pub fn to_state(&self) -> Result<OwnershipState, CompactOwnershipError> {
+        if self.run_owners.len() != self.run_lengths.len() {
+            return Err(CompactOwnershipError::RunCountMismatch {
+                owners: self.run_owners.len(),
+                lengths: self.run_lengths.len(),
+            });
+        }
+
+        let mut ownerships = Vec::new();
+        let mut tile_id = self.first_tile_id;
+
+        for (&owner, &length) in self.run_owners.iter().zip(&self.run_lengths) {
+            let next_tile_id = tile_id.checked_add(length).ok_or(CompactOwnershipError::TileIdOverflow)?;
+
+            if owner != 0 {
+                let country_id = self.countries.get(owner as usize - 1)
+                    .ok_or(CompactOwnershipError::UnknownOwner(owner))?;
+                if ownerships.len() + length as usize > MAX_OWNED_TILES {
+                    return Err(CompactOwnershipError::TooManyTiles);
+                }
+
+                ownerships.extend((tile_id..next_tile_id).map(|tile_id| Ownership {
+                    tile_id,
+                    country_id: country_id.clone(),
+                    timestamp_ns: 0,
+                    node_id: 0,
+                }));
+            }
+
+            tile_id = next_tile_id;
+        }
+
+        if !self.timestamp_offsets_ns.is_empty() {
+            if self.timestamp_offsets_ns.len() != ownerships.len() {
+                return Err(CompactOwnershipError::TimestampCountMismatch {
+                    offsets: self.timestamp_offsets_ns.len(),
+                    tiles: ownerships.len(),
+                });
+            }
+
+            for (ownership, &offset) in ownerships.iter_mut().zip(&self.timestamp_offsets_ns) {
+                ownership.timestamp_ns = self.base_timestamp_ns.checked_add(offset)
+                    .ok_or(CompactOwnershipError::TimestampOverflow)?;
+            }
+        }
+
+        if !self.node_indexes.is_empty() {
+            if self.node_indexes.len() != ownerships.len() {
+                return Err(CompactOwnershipError::NodeCountMismatch {
+                    indexes: self.node_indexes.len(),
+                    tiles: ownerships.len(),
+                });
+            }
+
+            for (ownership, &index) in ownerships.iter_mut().zip(&self.node_indexes) {
+                ownership.node_id = *self.nodes.get(index as usize)
+                    .ok_or(CompactOwnershipError::UnknownNode(index))?;
+            }
+        }
+
+        Ok(OwnershipState { ownerships })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ownership(tile_id: u32, country_id: &str, timestamp_ns: u64, node_id: u32) -> Ownership {
+        Ownership { tile_id, country_id: country_id.to_string(), timestamp_ns, node_id }
+    }
+
+    #[test]
+    fn test_runs_and_gaps_round_trip() {
+        let state = OwnershipState {
+            ownerships: vec![
+                ownership(7, "ru", 1_000_005, 9),
+                ownership(3, "fr", 1_000_000, 4),
+                ownership(4, "fr", 1_000_002, 9),
+                ownership(5, "fr", 1_000_001, 9),
+                ownership(8, "fr", 1_000_003, 4),
+            ],
+        };
+
+        let compact = CompactOwnershipState::from_state(&state, true);
+        assert_eq!(compact.countries, vec!["fr", "ru"]);
+        assert_eq!(compact.first_tile_id, 3);
+        assert_eq!(compact.run_owners, vec![1, 0, 2, 1]);
+        assert_eq!(compact.run_lengths, vec![3, 1, 1, 1]);
+        assert_eq!(compact.base_timestamp_ns, 1_000_000);
+        assert_eq!(compact.timestamp_offsets_ns, vec![0, 2, 1, 5, 3]);
+        assert_eq!(compact.nodes, vec![4, 9]);
+        assert_eq!(compact.node_indexes, vec![0, 1, 1, 1, 0]);
+
+        let mut expected = state.ownerships.clone();
+        expected.sort_by_key(|ownership| ownership.tile_id);
+        assert_eq!(compact.to_state().unwrap().ownerships, expected);
+
+        let without_timestamps = CompactOwnershipState::from_state(&state, false);
+        assert!(without_timestamps.timestamp_offsets_ns.is_empty());
+        assert!(without_timestamps.node_indexes.is_empty());
+        assert!(without_timestamps.to_state().unwrap().ownerships.iter()
+            .all(|ownership| ownership.timestamp_ns == 0 && ownership.node_id == 0));
+    }
+
+    #[test]
+    fn test_inconsistent_states_are_rejected() {
+        let compact = CompactOwnershipState {
+            countries: vec!["fr".to_string()],
+            run_owners: vec![2],
+            run_lengths: vec![1],
+            ..Default::default()
+        };
+
+        assert_eq!(compact.to_state(), Err(CompactOwnershipError::UnknownOwner(2)));
+
+        let overflowing = CompactOwnershipState {
+            countries: vec!["fr".to_string()],
+            first_tile_id: u32::MAX - 1,
+            run_owners: vec![1],
+            run_lengths: vec![3],
+            ..Default::default()
+        };
+        assert_eq!(overflowing.to_state(), Err(CompactOwnershipError::TileIdOverflow));
+
+        let oversized = CompactOwnershipState {
+            countries: vec!["fr".to_string()],
+            run_owners: vec![1, 0, 1],
+            run_lengths: vec![MAX_OWNED_TILES as u32, 1, 1],
+            ..Default::default()
+        };
+        assert_eq!(oversized.to_state(), Err(CompactOwnershipError::TooManyTiles));
+
+        let late = CompactOwnershipState {
+            countries: vec!["fr".to_string()],
+            run_owners: vec![1],
+            run_lengths: vec![1],
+            base_timestamp_ns: u64::MAX,
+            timestamp_offsets_ns: vec![1],
+            ..Default::default()
+        };
+        assert_eq!(late.to_state(), Err(CompactOwnershipError::TimestampOverflow));
+
+        let missing_nodes = CompactOwnershipState {
+            countries: vec!["fr".to_string()],
+            run_owners: vec![1],
+            run_lengths: vec![2],
+            nodes: vec![4],
+            node_indexes: vec![0],
+            ..Default::default()
+        };
+        assert_eq!(missing_nodes.to_state(), Err(CompactOwnershipError::NodeCountMismatch { indexes: 1, tiles: 2 }));
+
+        let unknown_node = CompactOwnershipState {
+            countries: vec!["fr".to_string()],
+            run_owners: vec![1],
+            run_lengths: vec![1],
+            nodes: vec![4],
+            node_indexes: vec![1],
+            ..Default::default()
+        };
+        assert_eq!(unknown_node.to_state(), Err(CompactOwnershipError::UnknownNode(1)));
+    }
+}
@@ -1,3 +1,5 @@
 pub mod clicks {
     include!(concat!(env!("OUT_DIR"), "/clicks.v1.rs"));
-}
\ No newline at end of file
+}
+
+pub mod compact;
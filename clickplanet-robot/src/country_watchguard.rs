@@ -1,5 +1,5 @@
 use crate::geolookup::CountryTilesMap;
-use clickplanet_client::TileCount;
+use clickplanet_client::{Subscription, TileCount};
 use futures_util::stream::BoxStream;
 use futures_util::{StreamExt, TryStreamExt};
 use rand::Rng;
@@ -48,7 +48,8 @@ impl CountryWatchguard {
 
 
     async fn monitor_updates(self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let updates: BoxStream<'_, clickplanet_proto::clicks::UpdateNotification> = self.client.listen_for_updates().await?;
+        let subscription = Subscription::tiles(self.country_tiles.iter().copied());
+        let updates: BoxStream<'_, clickplanet_proto::clicks::UpdateNotification> = self.client.listen_for_updates_matching(Some(subscription)).await?;
         let country_tiles = self.country_tiles.clone();
         let wanted_country = self.wanted_country.clone();
         let this = self.clone();
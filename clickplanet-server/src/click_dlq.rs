@@ -0,0 +1,94 @@
+use clap::{Parser, Subcommand};
+use clickplanet_server::dead_letter::{get, list, reinject, DeadLetter};
+
+/// Lists, inspects and re-injects the clicks of the dead letter stream
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(long, env = "NATS_URL", default_value = "nats://localhost:4222")]
+    nats_url: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// One line per dead-lettered click, oldest first
+    List {
+        /// First sequence of the dead letter stream to list
+        #[arg(long, default_value = "1")]
+        from: u64,
+
+        #[arg(long, default_value = "100")]
+        limit: usize,
+    },
+    /// Headers and decoded click of one dead letter
+    Show {
+        sequence: u64,
+    },
+    /// Publishes clicks back on their original subject and drops them from the dead letter stream
+    Reinject {
+        #[arg(required_unless_present = "all")]
+        sequences: Vec<u64>,
+
+        /// Every dead-lettered click
+        #[arg(long, conflicts_with = "sequences")]
+        all: bool,
+    },
+}
+
+fn summary(dead_letter: &DeadLetter) -> String {
+    let click = match dead_letter.click() {
+        Ok(click) => format!("tile {} country {} at {}", click.tile_id, click.country_id, click.timestamp_ns),
+        Err(_) => format!("undecodable payload of {} bytes", dead_letter.payload.len()),
+    };
+
+    format!("{}\t{}\t{}\t{}\t{}", dead_letter.sequence, dead_letter.consumer, dead_letter.source_subject, click, dead_letter.error)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let client = async_nats::connect(&args.nats_url).await?;
+    let jetstream = async_nats::jetstream::new(client);
+
+    match args.command {
+        Command::List { from, limit } => {
+            for dead_letter in list(&jetstream, from, limit).await? {
+                println!("{}", summary(&dead_letter));
+            }
+        }
+        Command::Show { sequence } => {
+            let dead_letter = get(&jetstream, sequence).await?;
+
+            println!("sequence:        {}", dead_letter.sequence);
+            println!("consumer:        {}", dead_letter.consumer);
+            println!("error:           {}", dead_letter.error);
+            println!("source subject:  {}", dead_letter.source_subject);
+            println!("source sequence: {}", dead_letter.source_sequence.map_or("-".to_string(), |sequence| sequence.to_string()));
+            println!("deliveries:      {}", dead_letter.delivery_count.map_or("-".to_string(), |count| count.to_string()));
+            match dead_letter.click() {
+                Ok(click) => println!("click:           {:?}", click),
+                Err(e) => println!("payload:         {} bytes, not a click ({})", dead_letter.payload.len(), e),
+            }
+        }
+        Command::Reinject { sequences, all } => {
+            let sequences = if all {
+                list(&jetstream, 1, usize::MAX).await?.iter().map(|dead_letter| dead_letter.sequence).collect()
+            } else {
+                sequences
+            };
+
+            for sequence in sequences {
+                match reinject(&jetstream, sequence).await {
+                    Ok(dead_letter) => println!("Re-injected {}", summary(&dead_letter)),
+                    Err(e) => eprintln!("Could not re-inject {}: {}", sequence, e),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use clickplanet_proto::clicks::Click;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use prost::Message;
+use thiserror::Error;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+use crate::click_service::ClickPublisher;
+use crate::shutdown::Shutdown;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Published clicks are dropped from the file once it is empty, or after this many if it never gets empty
+const COMPACT_AFTER: usize = 10_000;
+
+static OUTBOX_PENDING: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "clickplanet_click_outbox_pending",
+    "Clicks waiting in the local outbox for JetStream to take them"
+).unwrap());
+
+static OUTBOX_ENQUEUED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "clickplanet_click_outbox_enqueued_total",
+    "Clicks written to the local outbox instead of being published"
+).unwrap());
+
+static OUTBOX_PUBLISHED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "clickplanet_click_outbox_published_total",
+    "Clicks of the local outbox acknowledged by JetStream"
+).unwrap());
+
+static OUTBOX_RETRIES: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "clickplanet_click_outbox_retries_total",
+    "Failed attempts to publish the oldest click of the local outbox"
+).unwrap());
+
+#[derive(Error, Debug)]
+pub enum OutboxError {
+    #[error("The outbox already holds {0} clicks")]
+    Full(usize),
+    #[error("Outbox file error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Outbox task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+struct OutboxFile {
+    path: PathBuf,
+    file: File,
+    clicks: VecDeque<Click>,
+    // Clicks at the start of the file which were already published
+    published: usize,
+}
+
+impl OutboxFile {
+    fn open(path: PathBuf) -> Result<Self, OutboxError> {
+        let clicks = match std::fs::read(&path) {
+            Ok(bytes) => decode_clicks(&bytes),
+            Err(e) if e.kind() == ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Rewritten so that a record torn by a crash does not prevent appending
+        Ok(Self { file: rewrite(&path, &clicks)?, path, clicks, published: 0 })
+    }
+
+    fn append(&mut self, click: &Click) -> Result<(), OutboxError> {
+        self.file.write_all(&click.encode_length_delimited_to_vec())?;
+        self.file.sync_data()?;
+        self.clicks.push_back(click.clone());
+        Ok(())
+    }
+
+    fn mark_published(&mut self) -> Result<(), OutboxError> {
+        self.clicks.pop_front();
+        self.published += 1;
+
+        if self.clicks.is_empty() || self.published >= COMPACT_AFTER {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the published clicks from the file. After a crash, those still in it are published again
+    /// and JetStream drops them as duplicates, or the map ignores them as older than what it has.
+    fn compact(&mut self) -> Result<(), OutboxError> {
+        if self.published > 0 {
+            self.file = rewrite(&self.path, &self.clicks)?;
+            self.published = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the clicks of the file, up to the first incomplete record
+fn decode_clicks(mut bytes: &[u8]) -> VecDeque<Click> {
+    let mut clicks = VecDeque::new();
+
+    while !bytes.is_empty() {
+        match Click::decode_length_delimited(&mut bytes) {
+            Ok(click) => clicks.push_back(click),
+            Err(e) => {
+                warn!("Dropping the truncated end of the click outbox: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    clicks
+}
+
+/// Replaces the file atomically, and returns it opened for appending
+fn rewrite(path: &Path, clicks: &VecDeque<Click>) -> Result<File, OutboxError> {
+    let temporary = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temporary)?;
+        for click in clicks {
+            file.write_all(&click.encode_length_delimited_to_vec())?;
+        }
+        file.sync_all()?;
+    }
+    std::fs::rename(&temporary, path)?;
+
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+/// Clicks accepted while JetStream could not take them. They are kept in a file, so they survive a restart,
+/// and published in the order they were accepted once JetStream is back.
+pub struct ClickOutbox {
+    file: Arc<Mutex<OutboxFile>>,
+    // Clicks in the file, readable without waiting for the lock held across fsync
+    pending: Arc<AtomicUsize>,
+    max_clicks: usize,
+    notify: Notify,
+}
+
+impl ClickOutbox {
+    /// Loads the clicks a previous run could not publish
+    pub async fn open(path: impl Into<PathBuf>, max_clicks: usize) -> Result<Self, OutboxError> {
+        let path = path.into();
+        let file = tokio::task::spawn_blocking(move || OutboxFile::open(path)).await??;
+
+        if !file.clicks.is_empty() {
+            info!("{} clicks of the outbox are still to be published", file.clicks.len());
+        }
+        OUTBOX_PENDING.set(file.clicks.len() as i64);
+
+        Ok(Self {
+            pending: Arc::new(AtomicUsize::new(file.clicks.len())),
+            file: Arc::new(Mutex::new(file)),
+            max_clicks,
+            notify: Notify::new(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns once the click is on disk
+    pub async fn push(&self, click: &Click) -> Result<(), OutboxError> {
+        let file = self.file.clone();
+        let pending = self.pending.clone();
+        let click = click.clone();
+        let max_clicks = self.max_clicks;
+
+        let pending = tokio::task::spawn_blocking(move || {
+            let mut file = file.lock().unwrap();
+            if file.clicks.len() >= max_clicks {
+                return Err(OutboxError::Full(max_clicks));
+            }
+            file.append(&click)?;
+            pending.store(file.clicks.len(), Ordering::Release);
+            Ok(file.clicks.len())
+        }).await??;
+
+        OUTBOX_ENQUEUED.inc();
+        OUTBOX_PENDING.set(pending as i64);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Publishes the clicks one at a time, oldest first, until the shutdown is triggered.
+    /// A click is only dropped from the outbox once JetStream acknowledged it.
+    pub async fn run(&self, publisher: ClickPublisher, shutdown: Shutdown) {
+        self.publish_pending(publisher, shutdown).await;
+
+        let file = self.file.clone();
+        match tokio::task::spawn_blocking(move || file.lock().unwrap().compact()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Could not compact the click outbox: {:?}", e),
+            Err(e) => error!("Click outbox task failed: {:?}", e),
+        }
+    }
+
+    async fn publish_pending(&self, publisher: ClickPublisher, shutdown: Shutdown) {
+        loop {
+            // The lock can be held across an fsync by `push`, it is not waited for on a runtime thread
+            let file = self.file.clone();
+            let oldest = match tokio::task::spawn_blocking(move || file.lock().unwrap().clicks.front().cloned()).await {
+                Ok(oldest) => oldest,
+                Err(e) => {
+                    error!("Click outbox task failed: {:?}", e);
+                    return;
+                }
+            };
+
+            let Some(click) = oldest else {
+                tokio::select! {
+                    _ = self.notify.notified() => continue,
+                    _ = shutdown.wait() => return,
+                }
+            };
+
+            if let Err(e) = publisher.publish(&click).await {
+                OUTBOX_RETRIES.inc();
+                warn!("Could not publish click {} from the outbox, retrying: {:?}", click.click_id, e);
+
+                tokio::select! {
+                    _ = tokio::time::sleep(RETRY_INTERVAL) => continue,
+                    _ = shutdown.wait() => return,
+                }
+            }
+
+            let file = self.file.clone();
+            let pending = self.pending.clone();
+            let published = tokio::task::spawn_blocking(move || {
+                let mut file = file.lock().unwrap();
+                let result = file.mark_published().map(|_| file.clicks.len());
+                pending.store(file.clicks.len(), Ordering::Release);
+                result
+            }).await;
+
+            match published {
+                Ok(Ok(pending)) => {
+                    OUTBOX_PUBLISHED.inc();
+                    OUTBOX_PENDING.set(pending as i64);
+                }
+                // The click stays in the file and is published again after a restart, JetStream drops the duplicate
+                Ok(Err(e)) => error!("Could not update the click outbox: {:?}", e),
+                Err(e) => error!("Click outbox task failed: {:?}", e),
+            }
+
+            if shutdown.is_triggered() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn click(tile_id: i32) -> Click {
+        Click {
+            tile_id,
+            country_id: "fr".to_string(),
+            timestamp_ns: 1000 + tile_id as u64,
+            node_id: 0,
+            click_id: format!("click-{}", tile_id),
+        }
+    }
+
+    fn outbox_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("clickplanet-outbox-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn test_pending_clicks_survive_a_restart_in_order() {
+        let path = outbox_path("restart");
+
+        let outbox = ClickOutbox::open(&path, 10).await.unwrap();
+        for tile_id in 1..=3 {
+            outbox.push(&click(tile_id)).await.unwrap();
+        }
+        {
+            let mut file = outbox.file.lock().unwrap();
+            file.mark_published().unwrap();
+            file.compact().unwrap();
+        }
+        drop(outbox);
+
+        // A record torn by a crash while appending
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&click(4).encode_length_delimited_to_vec()[..5]).unwrap();
+
+        let outbox = ClickOutbox::open(&path, 10).await.unwrap();
+        let pending: Vec<Click> = outbox.file.lock().unwrap().clicks.iter().cloned().collect();
+        assert_eq!(pending, vec![click(2), click(3)]);
+
+        outbox.push(&click(5)).await.unwrap();
+        drop(outbox);
+        assert_eq!(ClickOutbox::open(&path, 10).await.unwrap().len(), 3);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_full_outbox_rejects_clicks() {
+        let path = outbox_path("full");
+
+        let outbox = ClickOutbox::open(&path, 1).await.unwrap();
+        outbox.push(&click(1)).await.unwrap();
+        assert!(matches!(outbox.push(&click(2)).await, Err(OutboxError::Full(1))));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use clickplanet_proto::clicks::ClickOutcome;
+use tokio::sync::oneshot;
+
+/// Clicks whose sender is waiting to learn how they were applied, keyed by click id.
+#[derive(Default)]
+pub struct ClickOutcomeRegistry {
+    pending: Mutex<HashMap<String, oneshot::Sender<ClickOutcome>>>,
+}
+
+impl ClickOutcomeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Must be called before the click is published, otherwise the outcome may be missed.
+    pub fn register(&self, click_id: &str) -> oneshot::Receiver<ClickOutcome> {
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(click_id.to_string(), sender);
+        receiver
+    }
+
+    pub fn forget(&self, click_id: &str) {
+        self.pending.lock().unwrap().remove(click_id);
+    }
+
+    /// Clicks are applied more than once (in-process and through JetStream): only the first outcome counts.
+    pub fn resolve(&self, click_id: &str, outcome: ClickOutcome) {
+        let sender = self.pending.lock().unwrap().remove(click_id);
+
+        if let Some(sender) = sender {
+            let _ = sender.send(outcome);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_first_outcome_wins() {
+        let registry = ClickOutcomeRegistry::new();
+        let receiver = registry.register("click");
+
+        registry.resolve("click", ClickOutcome::Captured);
+        registry.resolve("click", ClickOutcome::Superseded);
+
+        assert_eq!(receiver.await.unwrap(), ClickOutcome::Captured);
+    }
+
+    #[tokio::test]
+    async fn test_forgotten_click_is_not_resolved() {
+        let registry = ClickOutcomeRegistry::new();
+        let receiver = registry.register("click");
+
+        registry.forget("click");
+
+        assert!(receiver.await.is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use axum::async_trait;
 use thiserror::Error;
@@ -25,7 +26,66 @@ pub trait ClickRepository: Send + Sync {
         end_tile_id: u32,
     ) -> Result<OwnershipState, ClickRepositoryError>;
 
+    /// Tiles whose ownership changed after the `since_sequence`-th change this repository applied,
+    /// in apply order, at most `limit` of them. Sequences restart with the process.
+    async fn get_ownerships_since(
+        &self,
+        _since_sequence: u64,
+        _limit: usize,
+    ) -> Result<OwnershipChanges, ClickRepositoryError> {
+        Err(ClickRepositoryError::StorageError("this repository does not index its changes".to_string()))
+    }
+
     async fn save_click(&self, tile_id: u32, click: &Click) -> Result<Option<Ownership>, ClickRepositoryError>;
+
+    /// Saves clicks on distinct tiles, with the same ordering as `save_click`.
+    /// Returns how many of them were newer than the stored owner.
+    async fn save_clicks(&self, clicks: &[(u32, Click)]) -> Result<usize, ClickRepositoryError> {
+        let mut applied = 0;
+        for (tile_id, click) in clicks {
+            let previous = self.save_click(*tile_id, click).await?;
+            if previous.is_none_or(|previous| supersedes(click, &previous)) {
+                applied += 1;
+            }
+        }
+        Ok(applied)
+    }
+}
+
+/// A page of `get_ownerships_since`
+#[derive(Debug, Default)]
+pub struct OwnershipChanges {
+    pub ownerships: Vec<Ownership>,
+    /// Sequence of the last returned change, `since_sequence` when there is none
+    pub cursor: u64,
+}
+
+/// What clicks and the ownerships they left are ordered by on a tile
+pub trait ClickOrder {
+    fn order_key(&self) -> (u64, u32);
+}
+
+impl ClickOrder for Click {
+    fn order_key(&self) -> (u64, u32) {
+        (self.timestamp_ns, self.node_id)
+    }
+}
+
+impl ClickOrder for Ownership {
+    fn order_key(&self) -> (u64, u32) {
+        (self.timestamp_ns, self.node_id)
+    }
+}
+
+/// Order of the clicks on a tile, the same in every repository: the newest timestamp wins
+/// and the node id breaks ties, so that every replica keeps the same owner.
+pub fn click_order(click: &impl ClickOrder, current: &impl ClickOrder) -> Ordering {
+    click.order_key().cmp(&current.order_key())
+}
+
+/// Whether the click replaces the current owner, see `click_order`
+pub fn supersedes(click: &impl ClickOrder, current: &impl ClickOrder) -> bool {
+    click_order(click, current) == Ordering::Greater
 }
 
 #[derive(Error, Debug)]
@@ -1,71 +1,119 @@
 mod click_service;
+mod click_outbox;
 mod nats_commons;
 mod telemetry;
 mod redis_click_persistence;
 mod ownership_service;
 mod click_persistence;
 mod in_memory_click_persistence;
-
-use crate::click_service::{get_or_create_jet_stream, ClickService};
+mod file_click_persistence;
+mod click_outcomes;
+mod notification_log;
+mod ws_listener;
+mod subscription_filter;
+mod slow_consumer;
+mod rpc_codec;
+mod grpc_service;
+mod sse_listener;
+mod click_validation;
+mod rate_limit;
+mod metrics;
+mod health;
+mod shutdown;
+mod leaderboard_feed;
+mod idempotency;
+mod ownership_snapshot;
+mod hybrid_clock;
+
+use crate::click_service::{get_or_create_jet_stream, ClickPublisher, ClickService, ClickServiceError};
 use axum::{
-    extract::{Json, State},
-    extract::ws::{Message as WebsocketMessage},
-    extract::ws::{WebSocket},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::post,
     routing::get,
     Router,
 };
 
-use bytes::Bytes;
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use serde_json::{json, Value};
+use std::collections::HashMap;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock};
 use tokio;
 use tokio::net::TcpListener;
-use tracing::{error};
-use base64::{encode};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
 use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
 use std::{time::Duration};
-use axum::extract::WebSocketUpgrade;
-use axum::http::header::CONTENT_TYPE;
-use axum::http::{Method, Request};
-use axum::serve::Serve;
-use prost::Message;
-use tokio::sync::Mutex;
+use axum::http::header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH, RETRY_AFTER};
+use axum::http::{HeaderName, Method, Request};
+use async_nats::jetstream::consumer::DeliverPolicy;
 use tokio::sync::broadcast;
-use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::broadcast::Sender;
+use prometheus::{register_int_gauge, IntGauge};
+use tonic::service::Routes;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
 use clickplanet_proto::clicks::{Click, UpdateNotification};
-use clickplanet_proto::clicks::{LeaderboardResponse, LeaderboardEntry};
+use clickplanet_proto::clicks::{LeaderboardResponse, LeaderboardEntry, OwnershipsSinceResponse};
+use clickplanet_proto::clicks::{BatchRequest, ClickRequest, OwnershipsSinceRequest};
+use clickplanet_proto::clicks::click_planet_server::ClickPlanetServer;
 
+use crate::click_outbox::ClickOutbox;
+use crate::click_outcomes::ClickOutcomeRegistry;
+use crate::click_validation::ClickValidator;
 use crate::click_persistence::{ClickRepository, LeaderboardRepository, LeaderboardOnClicks, LeaderboardMaintainer};
+use crate::file_click_persistence::FileSnapshotStore;
+use crate::grpc_service::ClickPlanetGrpcService;
+use crate::hybrid_clock::HybridClock;
+use crate::health::{health_routes, ConditionCheck, NatsCheck, Readiness, RedisCheck};
+use crate::idempotency::{IdempotencyKey, IDEMPOTENCY_KEY_HEADER};
 use crate::in_memory_click_persistence::{PapayaClickRepository};
-use crate::nats_commons::ConsumerConfig;
+use crate::leaderboard_feed::LeaderboardFeed;
+use crate::metrics::metrics_response;
+use crate::nats_commons::{persisted_sequence, ConsumerConfig};
+use crate::notification_log::{NotificationLog, BROADCAST_QUEUED};
 use crate::ownership_service::OwnershipUpdateService;
+use crate::ownership_snapshot::{EncodedMessage, OwnershipSnapshot, OwnershipSnapshots};
 use crate::redis_click_persistence::{RedisClickRepository};
+use crate::rate_limit::{ClientIdentity, InMemoryRateLimitStore, RateLimitLayer, RateLimitStore, RateLimitStoreKind, RateLimiter, RedisRateLimitStore, RouteRateLimit};
+use crate::rpc_codec::{ProtoRequest, RpcFormat};
+use crate::slow_consumer::SlowConsumerPolicy;
+use crate::sse_listener::handle_sse_listen;
+use crate::shutdown::Shutdown;
 use crate::telemetry::{init_telemetry, TelemetryConfig};
+use crate::ws_listener::{handle_ws_upgrade, BatchConfig};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ClickPayload {
-    data: Vec<u8>,
-}
+const MAX_OWNERSHIPS_SINCE_LIMIT: usize = 10000;
+/// Longest a click request waits to be processed, over HTTP and gRPC
+const CLICK_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(Debug, Serialize, Deserialize)]
-struct BatchRequestPayload {
-    data: Vec<u8>,
-}
+static TILES_OWNED: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "clickplanet_tiles_owned",
+    "Tiles owned by a country in the in-memory map"
+).unwrap());
+
+static LEADERBOARD_COUNTRIES: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "clickplanet_leaderboard_countries",
+    "Countries owning at least one tile"
+).unwrap());
 
 #[derive(Clone)]
 struct AppState<T: ClickRepository + Send + Sync> {
     click_service: Arc<ClickService>,
     click_repository: Arc<T>,
     leaderboard_repo: Arc<dyn LeaderboardRepository>,
-    update_notifification_broadcaster: Arc<Sender<UpdateNotification>>,
+    notification_log: Arc<NotificationLog>,
     ownership_update_service: Arc<OwnershipUpdateService>,
+    slow_consumer_policy: SlowConsumerPolicy,
+    update_batching: BatchConfig,
+    click_validator: Arc<ClickValidator>,
+    leaderboard_feed: LeaderboardFeed,
+    ownership_snapshots: OwnershipSnapshots,
+    /// Websocket connections, which outlive the requests that upgraded them
+    ws_tasks: TaskTracker,
+    shutdown: Shutdown,
 }
 
 
@@ -86,73 +134,307 @@ struct Args {
 
     #[arg(long, env = "PORT", default_value = "3000")]
     port: u16,
+
+    /// Serve the gRPC API on its own port instead of next to the HTTP routes
+    #[arg(long, env = "GRPC_PORT")]
+    grpc_port: Option<u16>,
+
+    /// Run as a single process: no NATS, no Redis, clicks go through an in-process bus
+    #[arg(long, env = "EMBEDDED", default_value_t = false)]
+    embedded: bool,
+
+    /// Embedded mode only: load the map from this file at startup and persist it periodically
+    #[arg(long, env = "SNAPSHOT_FILE")]
+    snapshot_file: Option<String>,
+
+    #[arg(long, env = "SNAPSHOT_INTERVAL_SECS", default_value = "30")]
+    snapshot_interval_secs: u64,
+
+    /// How long a click waits for JetStream to acknowledge it before it is refused or written to the outbox
+    #[arg(long, env = "CLICK_PUBLISH_TIMEOUT_MS", default_value = "2000")]
+    click_publish_timeout_ms: u64,
+
+    /// Clicks JetStream cannot take are written to this file and published in order once it is back,
+    /// instead of being refused with 503
+    #[arg(long, env = "CLICK_OUTBOX_FILE")]
+    click_outbox_file: Option<String>,
+
+    /// Clicks the outbox may hold before new ones are refused with 503
+    #[arg(long, env = "CLICK_OUTBOX_MAX_CLICKS", default_value = "1000000")]
+    click_outbox_max_clicks: usize,
+
+    /// Orders the clicks of different servers taken at the same nanosecond, must differ between replicas.
+    /// Random when not set.
+    #[arg(long, env = "NODE_ID")]
+    node_id: Option<u32>,
+
+    /// How long in-flight requests, clicks and acknowledgments get to complete after SIGTERM
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS", default_value = "20")]
+    shutdown_timeout_secs: u64,
+
+    /// Number of recent ownership changes kept to resume websocket listeners
+    #[arg(long, env = "REPLAY_BUFFER_SIZE", default_value = "50000")]
+    replay_buffer_size: usize,
+
+    /// Tiles with unsent changes a websocket listener may accumulate before it is sent a resync instead
+    #[arg(long, env = "WS_MAX_PENDING_UPDATES", default_value = "10000")]
+    ws_max_pending_updates: usize,
+
+    /// Resyncs a websocket listener may need before it is disconnected as a slow consumer
+    #[arg(long, env = "WS_MAX_LAG_EVENTS", default_value = "3")]
+    ws_max_lag_events: u32,
+
+    /// How long notifications are gathered before being sent to websocket listeners using the batch subprotocol
+    #[arg(long, env = "WS_BATCH_WINDOW_MS", default_value = "50")]
+    ws_batch_window_ms: u64,
+
+    #[arg(long, env = "WS_BATCH_MAX_UPDATES", default_value = "1000")]
+    ws_batch_max_updates: usize,
+
+    /// Minimum time between two leaderboard messages sent to websocket listeners using the envelope subprotocol
+    #[arg(long, env = "LEADERBOARD_INTERVAL_MS", default_value = "1000")]
+    leaderboard_interval_ms: u64,
+
+    /// Minimum time between two rebuilds of the ownership snapshot served by /v2/rpc/ownerships
+    #[arg(long, env = "OWNERSHIPS_SNAPSHOT_INTERVAL_MS", default_value = "500")]
+    ownerships_snapshot_interval_ms: u64,
+
+    /// coordinates.json of the map, clicks on tiles beyond it are rejected. The server does not start without it.
+    #[arg(long, env = "COORDINATES_FILE", default_value = "coordinates.json")]
+    coordinates_file: String,
+
+    /// country_to_tiles.json, clicks for countries it does not list are rejected. Required unless COUNTRIES is set.
+    #[arg(long, env = "COUNTRY_TILES_FILE", default_value = "country_to_tiles.json")]
+    country_tiles_file: String,
+
+    /// Comma separated country codes accepted on click, takes precedence over COUNTRY_TILES_FILE
+    #[arg(long, env = "COUNTRIES", value_delimiter = ',')]
+    countries: Option<Vec<String>>,
+
+    /// Comma separated `path=requests_per_second[:burst]` limits, counted per client. Other routes are not limited.
+    /// gRPC methods are limited by their path, on the HTTP port and on GRPC_PORT alike.
+    #[arg(long, env = "RATE_LIMITS", value_delimiter = ',',
+        default_value = "/api/click=10:20,/v2/rpc/click=10:20,/clicks.v1.ClickPlanet/Click=10:20")]
+    rate_limits: Vec<RouteRateLimit>,
+
+    #[arg(long, env = "RATE_LIMIT_STORE", value_enum, default_value = "memory")]
+    rate_limit_store: RateLimitStoreKind,
+
+    /// Reverse proxies in front of the server, whose X-Forwarded-For entries identify clients. 0 ignores the header.
+    #[arg(long, env = "TRUSTED_PROXY_HOPS", default_value = "0")]
+    trusted_proxy_hops: usize,
+
+    /// Header carrying a session token, clients sending one are limited per session instead of per address
+    #[arg(long, env = "SESSION_HEADER")]
+    session_header: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
-    init_telemetry(TelemetryConfig {
+    let telemetry = init_telemetry(TelemetryConfig {
         otlp_endpoint: args.otlp_endpoint.clone(),
         service_name: args.service_name.clone(),
     }).await?;
 
-    run(&args).await?;
+    let result = run(&args).await;
+    telemetry.shutdown().await;
 
-    Ok(())
+    result
 }
 
 async fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    // Stops the listeners and the connections, the signal triggers it
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_signal();
+    // Stops the click consumers and the outbox, once no more click can be accepted
+    let worker_shutdown = Shutdown::new();
+    let ws_tasks = TaskTracker::new();
+
+    // Bound before loading the map, which can take a while: meanwhile the port only answers the probes
+    let listener = std::net::TcpListener::bind(format!("0.0.0.0:{}", args.port))?;
+    listener.set_nonblocking(true)?;
+    let (map_loaded, mut loading_probes) = serve_loading_probes(listener.try_clone()?)?;
+
     let (click_sender, _) = broadcast::channel(100000);
     let click_sender_ref = Arc::new(click_sender);
 
     let (update_notification_sender, _) = broadcast::channel(100000);
     let update_sender_ref: Arc<Sender<UpdateNotification>> = Arc::new(update_notification_sender);
+    let notification_log = Arc::new(NotificationLog::new(update_sender_ref.clone(), args.replay_buffer_size));
+
+    let jetstream = if args.embedded {
+        info!("Running in embedded mode, NATS and Redis are not used");
+        None
+    } else {
+        Some(Arc::new(get_or_create_jet_stream(args.nats_url.as_str()).await?))
+    };
+
+    // Read the persister position before loading the Redis snapshot: everything up to it is
+    // guaranteed to be in the snapshot, later clicks are replayed (replays are idempotent).
+    let deliver_policy = match &jetstream {
+        Some(jetstream) => match persisted_sequence(jetstream.clone()).await? {
+            Some(sequence) => DeliverPolicy::ByStartSequence { start_sequence: sequence + 1 },
+            None => DeliverPolicy::All,
+        },
+        None => DeliverPolicy::All,
+    };
 
-    let cold_repository: Arc<RedisClickRepository> = Arc::new(RedisClickRepository::new(args.redis_url.as_str()).await?);
-    let papaya_honey = PapayaClickRepository::populate_with(cold_repository).await?;
+    let snapshot_store = args.snapshot_file.as_ref()
+        .filter(|_| args.embedded)
+        .map(FileSnapshotStore::new);
+
+    let cold_repository: Option<Arc<RedisClickRepository>> = if args.embedded {
+        None
+    } else {
+        Some(Arc::new(RedisClickRepository::new(args.redis_url.as_str()).await?))
+    };
+
+    let papaya_honey = match &cold_repository {
+        Some(cold_repository) => PapayaClickRepository::populate_with(cold_repository.clone()).await?,
+        None => match &snapshot_store {
+            Some(store) => PapayaClickRepository::populate_from_state(store.load().await?).await?,
+            None => PapayaClickRepository::new(),
+        },
+    };
 
     let leaderboard_repo: Arc<dyn LeaderboardRepository> = Arc::new(LeaderboardOnClicks(papaya_honey.clone()));
     let click_repository: Arc<PapayaClickRepository> = Arc::new(papaya_honey.clone());
 
-    let jetstream = Arc::new(get_or_create_jet_stream(args.nats_url.as_str()).await?);
+    let final_snapshot_store = args.snapshot_file.as_ref()
+        .filter(|_| args.embedded)
+        .map(FileSnapshotStore::new);
 
-    let update_service = Arc::new(OwnershipUpdateService::new(
+    let periodic_snapshots = snapshot_store.map(|store| tokio::spawn(store.run_periodic(
+        click_repository.clone(),
+        Duration::from_secs(args.snapshot_interval_secs),
+        worker_shutdown.clone(),
+    )));
+
+    let click_outcomes = Arc::new(ClickOutcomeRegistry::new());
+
+    let click_publisher = jetstream.as_ref()
+        .map(|jetstream| ClickPublisher::new(jetstream.clone(), Duration::from_millis(args.click_publish_timeout_ms)));
+    let click_outbox = match &args.click_outbox_file {
+        Some(path) if click_publisher.is_some() => Some(Arc::new(ClickOutbox::open(path, args.click_outbox_max_clicks).await?)),
+        _ => None,
+    };
+
+    // Clicks are never taken unchecked: missing map files stop the startup
+    let tile_count = ClickValidator::tile_count_from_coordinates(&args.coordinates_file)?;
+    let countries = match &args.countries {
+        Some(countries) => countries.iter().map(|country| country.trim().to_lowercase()).collect(),
+        None => ClickValidator::countries_from_country_tiles(&args.country_tiles_file)?,
+    };
+    let click_validator = Arc::new(ClickValidator::new(Some(tile_count), Some(countries)));
+
+    let node_id = args.node_id.unwrap_or_else(|| uuid::Uuid::new_v4().as_u128() as u32);
+    info!("Ordering clicks as node {}", node_id);
+    let clock = Arc::new(HybridClock::new(node_id));
+
+    let mut update_service = OwnershipUpdateService::new(
         click_repository.clone(),
         click_repository.clone(),
         click_sender_ref.clone(),
-        update_sender_ref.clone(),
-        jetstream.clone(),
+        notification_log.clone(),
+        click_outcomes.clone(),
+        clock.clone(),
         Some(ConsumerConfig {
             concurrent_processors: 2,
             ack_wait: Duration::from_secs(20),
+            deliver_policy,
             ..Default::default()
         })
-    ));
+    );
+    if let Some(jetstream) = jetstream.clone() {
+        update_service = update_service.with_jetstream(jetstream);
+    }
+    let update_service = Arc::new(update_service);
 
     let state = AppState {
-        click_service: Arc::new(ClickService::new(jetstream.clone(), click_sender_ref.clone()).await.unwrap()),
+        click_service: Arc::new(ClickService::new(click_publisher.clone(), click_outbox.clone(), click_sender_ref.clone(), click_outcomes.clone(), clock.clone()).await.unwrap()),
         click_repository: click_repository.clone(),
         leaderboard_repo: leaderboard_repo.clone(),
-        update_notifification_broadcaster: update_sender_ref.clone(),
+        notification_log: notification_log.clone(),
         ownership_update_service: update_service.clone(),
+        slow_consumer_policy: SlowConsumerPolicy {
+            max_pending_updates: args.ws_max_pending_updates,
+            max_lag_events: args.ws_max_lag_events,
+        },
+        update_batching: BatchConfig {
+            window: Duration::from_millis(args.ws_batch_window_ms),
+            max_updates: args.ws_batch_max_updates,
+        },
+        click_validator: click_validator.clone(),
+        leaderboard_feed: LeaderboardFeed::spawn(
+            leaderboard_repo.clone(),
+            Duration::from_millis(args.leaderboard_interval_ms),
+            shutdown.clone(),
+        ),
+        ownership_snapshots: OwnershipSnapshots::spawn(
+            click_repository.clone(),
+            Duration::from_millis(args.ownerships_snapshot_interval_ms),
+            shutdown.clone(),
+        ),
+        ws_tasks: ws_tasks.clone(),
+        shutdown: shutdown.clone(),
+    };
+
+    let mut readiness = Readiness::new()
+        .with(ConditionCheck::new("ownership_consumer", {
+            let update_service = update_service.clone();
+            move || update_service.is_running()
+        }, "the ownership consumer is not running"));
+    if let Some(jetstream) = &jetstream {
+        readiness = readiness.with(NatsCheck(jetstream.clone()));
+    }
+    if let Some(cold_repository) = &cold_repository {
+        readiness = readiness.with(RedisCheck(cold_repository.clone()));
+    }
+
+    let rate_limit_store: Arc<dyn RateLimitStore> = match args.rate_limit_store {
+        RateLimitStoreKind::Memory => Arc::new(InMemoryRateLimitStore::new()),
+        RateLimitStoreKind::Redis => Arc::new(RedisRateLimitStore::new(args.redis_url.as_str())?),
+    };
+    let client_identity = ClientIdentity {
+        trusted_proxy_hops: args.trusted_proxy_hops,
+        session_header: args.session_header.as_deref().map(HeaderName::try_from).transpose()?,
     };
+    let rate_limit_layer = RateLimitLayer::new(RateLimiter::new(rate_limit_store, client_identity, args.rate_limits.clone()));
 
-    let app = Router::new()
+    let grpc_service = ClickPlanetServer::new(ClickPlanetGrpcService::new(state.clone()));
+
+    let mut app = Router::new()
         .route("/api/click", post(handle_click))
         .route("/v2/rpc/click", post(handle_click))
         .route("/api/ownerships-by-batch", post(handle_get_ownerships_by_batch))
         .route("/v2/rpc/ownerships-by-batch", post(handle_get_ownerships_by_batch))
         .route("/v2/rpc/ownerships", get(handle_get_ownerships))
+        .route("/v2/rpc/ownerships-compact", get(handle_get_compact_ownerships))
+        .route("/v2/rpc/ownerships-since", post(handle_get_ownerships_since))
         .route("/v2/rpc/leaderboard", get(handle_get_leaderboard))
         .route("/ws/listen", get(handle_ws_upgrade))
         .route("/v2/ws/listen", get(handle_ws_upgrade))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-                .allow_headers([CONTENT_TYPE])
-        )
+        .route("/v2/sse/listen", get(handle_sse_listen))
+        .route("/metrics", get({
+            let click_repository = click_repository.clone();
+            let click_sender = click_sender_ref.clone();
+            let update_sender = update_sender_ref.clone();
+            move || handle_metrics(click_repository, click_sender, update_sender)
+        }))
+        .with_state(state)
+        .merge(health_routes(readiness));
+
+    // gRPC clients talk HTTP/2 with prior knowledge, which the HTTP listener also accepts
+    if args.grpc_port.is_none() {
+        app = app.merge(Routes::new(grpc_service.clone()).into_axum_router());
+    }
+
+    // Inside the CORS layer so browsers can read the Retry-After of rejected requests
+    let app = app
+        .layer(rate_limit_layer.clone())
+        .layer(cors_layer())
         .layer(TraceLayer::new_for_http()
             .make_span_with(|request: &Request<_>| {
                 tracing::info_span!(
@@ -162,104 +444,275 @@ async fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
                     version = ?request.version(),
                 )
             })
-        )
-        .with_state(state);
+        );
 
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?;
+    // The probes server hands the port over once its connections are closed
+    map_loaded.trigger();
+    if tokio::time::timeout(Duration::from_secs(5), &mut loading_probes).await.is_err() {
+        loading_probes.abort();
+        let _ = loading_probes.await;
+    }
+    let listener = TcpListener::from_std(listener)?;
     println!("Server listening on 0.0.0.0:{}", args.port);
 
-    let server: Serve<Router, Router> = axum::serve(listener, app);
-    let update_service_clone = update_service.clone();
-    let update_service_handle = update_service_clone.run();
-
-    tokio::select! {
-        result = server => {
-            if let Err(e) = result {
-                error!("Server error: {:?}", e);
-                return Err(e.to_string().into());
-            } else {
-                error!("Unexpected server exit");
-            }
+    // The peer address identifies clients for the rate limits
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown({
+            let shutdown = shutdown.clone();
+            async move { shutdown.wait().await }
+        });
+
+    let mut server_tasks = JoinSet::new();
+    let mut worker_tasks = JoinSet::new();
+    server_tasks.spawn(async move {
+        server.await.map_err(|e| format!("Server error: {:?}", e))
+    });
+
+    // Served by axum as well, so that the gRPC port gets the same rate limits and CORS as the HTTP one
+    if let Some(port) = args.grpc_port {
+        let grpc_app = Routes::new(grpc_service).into_axum_router()
+            .layer(rate_limit_layer)
+            .layer(cors_layer());
+        let grpc_listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+        println!("gRPC server listening on 0.0.0.0:{}", port);
+
+        let grpc_server = axum::serve(grpc_listener, grpc_app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown({
+                let shutdown = shutdown.clone();
+                async move { shutdown.wait().await }
+            });
+        server_tasks.spawn(async move {
+            grpc_server.await.map_err(|e| format!("gRPC server error: {:?}", e))
+        });
+    }
+    worker_tasks.spawn({
+        let update_service = update_service.clone();
+        let shutdown = worker_shutdown.clone();
+        async move {
+            update_service.run(shutdown).await.map_err(|e| format!("Ownership update service error: {:?}", e))
         }
-        result = update_service_handle => {
-            if let Err(e) = result {
-               error!("Ownership update service error: {:?}", e);
-               return Err(e.to_string().into());
-            } else {
-               error!("Unexpected update service exit");
+    });
+
+    if let (Some(outbox), Some(publisher)) = (click_outbox, click_publisher) {
+        let shutdown = worker_shutdown.clone();
+        worker_tasks.spawn(async move {
+            outbox.run(publisher, shutdown).await;
+            Ok(())
+        });
+    }
+
+    let result = tokio::select! {
+        Some(exit) = server_tasks.join_next() => task_exit(exit, &shutdown),
+        Some(exit) = worker_tasks.join_next() => task_exit(exit, &shutdown),
+        _ = shutdown.wait() => Ok(()),
+    };
+
+    // Listeners stop accepting and finish their requests, websockets send their close frame, then the
+    // clicks already accepted are applied and published before the consumers and the outbox stop
+    shutdown.trigger();
+    let drained = tokio::time::timeout(Duration::from_secs(args.shutdown_timeout_secs), async {
+        drain(&mut server_tasks).await;
+        ws_tasks.close();
+        ws_tasks.wait().await;
+
+        worker_shutdown.trigger();
+        drain(&mut worker_tasks).await;
+    }).await;
+
+    if drained.is_err() {
+        warn!("Shutdown deadline reached, dropping the remaining work");
+        server_tasks.abort_all();
+        worker_tasks.abort_all();
+    }
+
+    // The final snapshot would race with a periodic one writing the same file
+    worker_shutdown.trigger();
+    if let Some(task) = periodic_snapshots {
+        let _ = task.await;
+    }
+
+    if let Some(store) = final_snapshot_store {
+        if let Err(e) = store.save_from(click_repository.as_ref()).await {
+            error!("Could not save the final snapshot: {:?}", e);
+        }
+    }
+
+    info!("Server stopped");
+    result
+}
+
+/// Answers `/healthz`, and `/readyz` with "not ready", until the returned shutdown is triggered
+fn serve_loading_probes(listener: std::net::TcpListener) -> std::io::Result<(Shutdown, JoinHandle<()>)> {
+    let loaded = Shutdown::new();
+    let readiness = Readiness::new()
+        .with(ConditionCheck::new("ownership_map", || false, "ownerships are still being loaded"));
+
+    let server = axum::serve(TcpListener::from_std(listener)?, health_routes(readiness))
+        .with_graceful_shutdown({
+            let loaded = loaded.clone();
+            async move { loaded.wait().await }
+        });
+    let task = tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Probes server error: {:?}", e);
+        }
+    });
+
+    Ok((loaded, task))
+}
+
+fn task_exit(exit: Result<Result<(), String>, JoinError>, shutdown: &Shutdown) -> Result<(), Box<dyn std::error::Error>> {
+    match exit {
+        Ok(Err(e)) => {
+            error!("{}", e);
+            Err(e.into())
+        }
+        Ok(Ok(())) => {
+            if !shutdown.is_triggered() {
+                error!("Unexpected server task exit");
             }
+            Ok(())
+        }
+        Err(e) => {
+            error!("Server task failed: {:?}", e);
+            Err(e.to_string().into())
         }
     }
+}
+
+async fn drain(tasks: &mut JoinSet<Result<(), String>>) {
+    while let Some(exit) = tasks.join_next().await {
+        if let Ok(Err(e)) = exit {
+            error!("{}", e);
+        }
+    }
+}
 
-    Ok(())
+fn cors_layer() -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_headers([CONTENT_TYPE, IF_NONE_MATCH, HeaderName::from_static("last-event-id"), HeaderName::from_static(IDEMPOTENCY_KEY_HEADER)])
+        .expose_headers([RETRY_AFTER, ETAG])
+}
+
+/// Gauges derived from the map and the channels are read at scrape time
+async fn handle_metrics(
+    click_repository: Arc<PapayaClickRepository>,
+    click_sender: Arc<Sender<Click>>,
+    update_sender: Arc<Sender<UpdateNotification>>,
+) -> impl IntoResponse {
+    TILES_OWNED.set(click_repository.tile_count() as i64);
+    if let Ok(scores) = click_repository.leaderboard().await {
+        LEADERBOARD_COUNTRIES.set(scores.len() as i64);
+    }
+    BROADCAST_QUEUED.with_label_values(&["clicks"]).set(click_sender.len() as i64);
+    BROADCAST_QUEUED.with_label_values(&["updates"]).set(update_sender.len() as i64);
+
+    metrics_response()
 }
 
 async fn handle_click<T: ClickRepository>(
     State(state): State<AppState<T>>,
-    Json(payload): Json<ClickPayload>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let click_request = clickplanet_proto::clicks::ClickRequest::decode(Bytes::from(payload.data))
-        .map_err(|_| {
-            StatusCode::BAD_REQUEST
-        })?;
+    format: RpcFormat,
+    IdempotencyKey(idempotency_key): IdempotencyKey,
+    ProtoRequest(click_request): ProtoRequest<ClickRequest>,
+) -> Result<Response, StatusCode> {
+    if let Err(validation_error) = state.click_validator.validate(&click_request) {
+        return Ok(validation_error.into_response());
+    }
 
-    tokio::time::timeout(
-        Duration::from_secs(10),
-        state.click_service.process_click(click_request)
+    let response = tokio::time::timeout(
+        CLICK_TIMEOUT,
+        state.click_service.process_click(click_request, idempotency_key.as_deref())
     )
         .await
         .map_err(|e| {
             error!("Timeout error while clicking: {:?}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?
-        .map_err(|e| {
-            error!("Error while processing click: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+        .map_err(|e| match e {
+            ClickServiceError::Unavailable(reason) => {
+                warn!("Click refused: {}", reason);
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ClickServiceError::Idempotency(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            e => {
+                error!("Error while processing click: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
         })?;
 
-
-    Ok(axum::Json(serde_json::json!({})))
+    Ok(format.respond(&response))
 }
 
 async fn handle_get_ownerships<T: ClickRepository>(
     State(state): State<AppState<T>>,
-) -> Result<Json<Value>, StatusCode> {
-    let response = tokio::time::timeout(
+    format: RpcFormat,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let snapshot = latest_snapshot(&state).await?;
+
+    respond_with_snapshot(snapshot, |snapshot| &snapshot.full, format, headers).await
+}
+
+#[derive(Debug, Deserialize)]
+struct CompactOwnershipsParams {
+    #[serde(default)]
+    timestamps: bool,
+}
+
+async fn handle_get_compact_ownerships<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Query(params): Query<CompactOwnershipsParams>,
+    format: RpcFormat,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let snapshot = latest_snapshot(&state).await?;
+
+    if params.timestamps {
+        respond_with_snapshot(snapshot, |snapshot| &snapshot.compact_with_timestamps, format, headers).await
+    } else {
+        respond_with_snapshot(snapshot, |snapshot| &snapshot.compact, format, headers).await
+    }
+}
+
+/// Off the async workers: the first request for a coding of the snapshot compresses it
+async fn respond_with_snapshot(
+    snapshot: Arc<OwnershipSnapshot>,
+    message: fn(&OwnershipSnapshot) -> &EncodedMessage,
+    format: RpcFormat,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    tokio::task::spawn_blocking(move || message(&snapshot).respond(format, &headers))
+        .await
+        .map_err(|e| {
+            error!("Error while responding with the ownership snapshot: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+async fn latest_snapshot<T: ClickRepository>(state: &AppState<T>) -> Result<Arc<OwnershipSnapshot>, StatusCode> {
+    tokio::time::timeout(
         Duration::from_secs(5),
-        state.click_repository.get_ownerships(),
+        state.ownership_snapshots.latest(),
     )
         .await
         .map_err(|e| {
-            error!("Timeout error while calling get_ownerships: {:?}", e);
+            error!("Timeout error while waiting for the ownership snapshot: {:?}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?
-        .map_err(|e| {
-            error!("Error while processing get_ownerships: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-
-    let mut response_bytes = Vec::new();
-
-    response
-        .encode(&mut response_bytes)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let base64_data = encode(&response_bytes);
-
-    let payload = json!({
-        "data": base64_data,
-    });
-
-    Ok(axum::Json(payload))
+        .ok_or_else(|| {
+            error!("The ownership snapshot is no longer built");
+            StatusCode::SERVICE_UNAVAILABLE
+        })
 }
 
 async fn handle_get_ownerships_by_batch<T: ClickRepository>(
     State(state): State<AppState<T>>,
-    Json(payload): Json<BatchRequestPayload>,
-) -> Result<Json<Value>, StatusCode> {
-    let batch_request = clickplanet_proto::clicks::BatchRequest::decode(Bytes::from(payload.data))
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
+    format: RpcFormat,
+    ProtoRequest(batch_request): ProtoRequest<BatchRequest>,
+) -> Result<Response, StatusCode> {
     let response = tokio::time::timeout(
         Duration::from_secs(5),
         state.click_repository.get_ownerships_by_batch(
@@ -277,75 +730,51 @@ async fn handle_get_ownerships_by_batch<T: ClickRepository>(
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-    let mut response_bytes = Vec::new();
-
-    response
-        .encode(&mut response_bytes)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let base64_data = encode(&response_bytes);
-
-    let payload = json!({
-        "data": base64_data,
-    });
-
-    Ok(axum::Json(payload))
+    Ok(format.respond(&response))
 }
 
-
-async fn handle_ws_upgrade<T: ClickRepository+ 'static>(
-    ws: WebSocketUpgrade,
+async fn handle_get_ownerships_since<T: ClickRepository>(
     State(state): State<AppState<T>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_ws_connection(socket, state))
-}
+    format: RpcFormat,
+    ProtoRequest(since_request): ProtoRequest<OwnershipsSinceRequest>,
+) -> Result<Response, StatusCode> {
+    let limit = match since_request.limit as usize {
+        0 => MAX_OWNERSHIPS_SINCE_LIMIT,
+        limit => limit.min(MAX_OWNERSHIPS_SINCE_LIMIT),
+    };
 
-async fn handle_ws_connection<T: ClickRepository>(socket: WebSocket, state: AppState<T>) {
-    let (sender, mut receiver) = socket.split();
-    let sender_arc = Arc::new(Mutex::new(sender));
-
-    let mut update_notification_subscription: Receiver<UpdateNotification> = state.update_notifification_broadcaster.subscribe();
-    let sender_arc_clone = sender_arc.clone();
-
-    let mut send_task = tokio::spawn(async move {
-        while let Ok(notification) = update_notification_subscription.recv().await {
-            let mut buf = Vec::new();
-            if notification.encode(&mut buf).is_ok() {
-                let mut sender = sender_arc.lock().await;
-                if let Err(e) = sender.send(WebsocketMessage::Binary(buf)).await {
-                    eprintln!("Error sending WebSocket message: {}", e);
-                    break;
-                }
-            }
-        }
-    });
+    // Sequences restart with the process, a cursor from another one starts over
+    let epoch = state.notification_log.epoch();
+    let since_sequence = if since_request.epoch == epoch { since_request.since_sequence } else { 0 };
 
+    let changes = tokio::time::timeout(
+        Duration::from_secs(5),
+        state.click_repository.get_ownerships_since(since_sequence, limit),
+    )
+        .await
+        .map_err(|e| {
+            error!("Timeout error while calling get_ownerships_since: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map_err(|e| {
+            error!("Error while processing get_ownerships_since: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(message)) = receiver.next().await {
-            match message {
-                WebsocketMessage::Ping(payload) => {
-                    let mut sender = sender_arc_clone.lock().await;
-                    if let Err(e) = sender.send(WebsocketMessage::Pong(payload)).await {
-                        eprintln!("Error sending pong: {}", e);
-                        break;
-                    }
-                }
-                WebsocketMessage::Close(_) => break,
-                _ => {}
-            }
-        }
-    });
+    let response = OwnershipsSinceResponse {
+        cursor: changes.cursor,
+        has_more: changes.ownerships.len() >= limit,
+        ownerships: changes.ownerships,
+        epoch,
+    };
 
-    tokio::select! {
-        _ = &mut send_task => recv_task.abort(),
-        _ = &mut recv_task => send_task.abort(),
-    }
+    Ok(format.respond(&response))
 }
 
 async fn handle_get_leaderboard<T: ClickRepository>(
     State(state): State<AppState<T>>,
-) -> Result<Json<Value>, StatusCode> {
+    format: RpcFormat,
+) -> Result<Response, StatusCode> {
     let leaderboard_data = tokio::time::timeout(
         Duration::from_secs(5),
         state.leaderboard_repo.leaderboard(),
@@ -360,12 +789,16 @@ async fn handle_get_leaderboard<T: ClickRepository>(
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
+    Ok(format.respond(&leaderboard_response(leaderboard_data)))
+}
+
+fn leaderboard_response(scores: HashMap<String, u32>) -> LeaderboardResponse {
     let mut response = LeaderboardResponse {
         entries: Vec::new(),
     };
 
     // Convert HashMap to vec and sort by score in descending order
-    let mut entries: Vec<_> = leaderboard_data
+    let mut entries: Vec<_> = scores
         .into_iter()
         .map(|(country_id, score)| LeaderboardEntry {
             country_id,
@@ -376,16 +809,5 @@ async fn handle_get_leaderboard<T: ClickRepository>(
     entries.sort_by(|a, b| b.score.cmp(&a.score));
     response.entries = entries;
 
-    let mut response_bytes = Vec::new();
     response
-        .encode(&mut response_bytes)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let base64_data = encode(&response_bytes);
-
-    let payload = json!({
-        "data": base64_data,
-    });
-
-    Ok(axum::Json(payload))
-}
\ No newline at end of file
+}
@@ -1,18 +1,95 @@
 use async_nats::{jetstream, ConnectError};
 use prost::Message;
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use async_nats::jetstream::context::Publish;
 use async_nats::jetstream::Context;
+use prometheus::{register_histogram, register_int_counter, register_int_counter_vec, Histogram, IntCounter, IntCounterVec};
 use thiserror::Error;
 use tokio::sync::broadcast::Sender;
 use tracing::{info, instrument, warn, Span};
 use uuid::Uuid;
-use clickplanet_proto::clicks::{Click};
-use crate::nats_commons::{CLICK_STREAM_NAME, CLICK_SUBJECT_PREFIX};
+use clickplanet_proto::clicks::{Click, ClickOutcome};
+use crate::click_outbox::ClickOutbox;
+use crate::click_outcomes::ClickOutcomeRegistry;
+use crate::hybrid_clock::HybridClock;
+use crate::idempotency::{click_id_for_key, IdempotencyCache, IdempotencyError, IDEMPOTENCY_WINDOW};
+use clickplanet_server::dead_letter::get_or_create_dlq_stream;
+use clickplanet_server::streams::{CLICK_STREAM_NAME, CLICK_SUBJECT_PREFIX};
 
-pub struct ClickService {
+const APPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CLICKS_ACCEPTED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "clickplanet_clicks_accepted_total",
+    "Clicks that passed validation and were published"
+).unwrap());
+
+static NATS_PUBLISH_SECONDS: LazyLock<Histogram> = LazyLock::new(|| register_histogram!(
+    "clickplanet_nats_publish_duration_seconds",
+    "Time to publish a click to JetStream and get its acknowledgment"
+).unwrap());
+
+static NATS_PUBLISH_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "clickplanet_nats_publish_failures_total",
+    "Click publications JetStream did not acknowledge, by reason",
+    &["reason"]
+).unwrap());
+
+static CLICKS_UNAVAILABLE: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "clickplanet_clicks_unavailable_total",
+    "Clicks refused with 503 because neither JetStream nor the outbox could take them"
+).unwrap());
+
+/// Publishes clicks to JetStream and waits for their acknowledgment.
+#[derive(Clone)]
+pub struct ClickPublisher {
     jetstream: Arc<jetstream::Context>,
+    timeout: Duration,
+}
+
+impl ClickPublisher {
+    pub fn new(jetstream: Arc<jetstream::Context>, timeout: Duration) -> Self {
+        Self { jetstream, timeout }
+    }
+
+    /// Returns once JetStream stored the click. The click id is the message id,
+    /// so JetStream drops the duplicates of a retried click within its duplicate window:
+    /// true when it already had the click.
+    pub async fn publish(&self, click: &Click) -> Result<bool, ClickServiceError> {
+        let subject = format!("{}{}", CLICK_SUBJECT_PREFIX, click.tile_id);
+        let publish = Publish::build()
+            .payload(click.encode_to_vec().into())
+            .message_id(&click.click_id);
+
+        let publish_start = Instant::now();
+        let result = tokio::time::timeout(self.timeout, async {
+            self.jetstream.send_publish(subject, publish).await?.await
+        }).await;
+        NATS_PUBLISH_SECONDS.observe(publish_start.elapsed().as_secs_f64());
+
+        match result {
+            Ok(Ok(ack)) => Ok(ack.duplicate),
+            Ok(Err(e)) => {
+                NATS_PUBLISH_FAILURES.with_label_values(&["error"]).inc();
+                Err(ClickServiceError::Unavailable(e.to_string()))
+            }
+            Err(_) => {
+                NATS_PUBLISH_FAILURES.with_label_values(&["timeout"]).inc();
+                Err(ClickServiceError::Unavailable(format!("no acknowledgment within {:?}", self.timeout)))
+            }
+        }
+    }
+}
+
+pub struct ClickService {
+    /// Absent in embedded mode, where the in-memory broadcast channel is the only bus
+    publisher: Option<ClickPublisher>,
+    /// Takes the clicks JetStream cannot, they are refused otherwise
+    outbox: Option<Arc<ClickOutbox>>,
     sender: Arc<Sender<Click>>,
+    outcomes: Arc<ClickOutcomeRegistry>,
+    idempotency: IdempotencyCache,
+    clock: Arc<HybridClock>,
 }
 
 #[derive(Error, Debug)]
@@ -21,8 +98,10 @@ pub enum ClickServiceError {
     ConnectionError(#[from] ConnectError),  // Changed to specific ConnectError
     #[error("Failed to create stream: {0}")]
     StreamCreationError(String),
-    #[error("Nats ack error: {0}")]
-    NatsError(String)
+    #[error("Clicks cannot be taken for now: {0}")]
+    Unavailable(String),
+    #[error(transparent)]
+    Idempotency(#[from] IdempotencyError),
 }
 
 pub async fn get_or_create_jet_stream(nats_url: &str) -> Result<Context, ClickServiceError> {
@@ -33,6 +112,7 @@ pub async fn get_or_create_jet_stream(nats_url: &str) -> Result<Context, ClickSe
         name: CLICK_STREAM_NAME.to_string(),
         subjects: vec![format!("{}*", CLICK_SUBJECT_PREFIX).to_string()],
         max_age: Duration::from_secs(8 * 60 * 60),
+        duplicate_window: IDEMPOTENCY_WINDOW.as_nanos() as i64,
         discard: async_nats::jetstream::stream::DiscardPolicy::Old,
         ..Default::default()
     };
@@ -52,13 +132,67 @@ pub async fn get_or_create_jet_stream(nats_url: &str) -> Result<Context, ClickSe
         }
     }
 
+    get_or_create_dlq_stream(&jetstream)
+        .await
+        .map_err(|e| ClickServiceError::StreamCreationError(e.to_string()))?;
+
     Ok(jetstream)
 }
 
 
 impl ClickService {
-    pub async fn new(jetstream: Arc<Context>, sender: Arc<Sender<Click>>) -> Result<Self, ClickServiceError> {
-        Ok(Self { jetstream, sender })
+    pub async fn new(publisher: Option<ClickPublisher>, outbox: Option<Arc<ClickOutbox>>, sender: Arc<Sender<Click>>,
+                     outcomes: Arc<ClickOutcomeRegistry>, clock: Arc<HybridClock>) -> Result<Self, ClickServiceError> {
+        Ok(Self { publisher, outbox, sender, outcomes, idempotency: IdempotencyCache::default(), clock })
+    }
+
+    /// Publishes the click, or writes it to the outbox when JetStream cannot take it.
+    /// While the outbox holds clicks, new ones are queued behind them to keep the order.
+    /// Direct publishes are concurrent though: a click still waiting for its acknowledgment does not hold back
+    /// the next ones, and ends up in the outbox behind them if it times out. Only the order of clicks on the same
+    /// tile matters, and there the timestamps decide, whatever the order JetStream stored them in.
+    /// True when JetStream already had the click.
+    async fn publish(&self, click: &Click) -> Result<bool, ClickServiceError> {
+        let Some(publisher) = &self.publisher else {
+            return Ok(false);
+        };
+
+        let result = match &self.outbox {
+            Some(outbox) if !outbox.is_empty() => self.push_to_outbox(outbox, click).await.map(|()| false),
+            Some(outbox) => match publisher.publish(click).await {
+                Ok(duplicate) => Ok(duplicate),
+                Err(e) => {
+                    warn!("JetStream did not take click {}, writing it to the outbox: {:?}", click.click_id, e);
+                    self.push_to_outbox(outbox, click).await.map(|()| false)
+                }
+            },
+            None => publisher.publish(click).await,
+        };
+
+        if result.is_err() {
+            CLICKS_UNAVAILABLE.inc();
+        }
+        result
+    }
+
+    async fn push_to_outbox(&self, outbox: &ClickOutbox, click: &Click) -> Result<(), ClickServiceError> {
+        outbox.push(click)
+            .await
+            .map_err(|e| ClickServiceError::Unavailable(e.to_string()))
+    }
+
+    /// Retries of a click sent with the same idempotency key get the response of the first one
+    pub async fn process_click(
+        &self,
+        request: clickplanet_proto::clicks::ClickRequest,
+        idempotency_key: Option<&str>,
+    ) -> Result<clickplanet_proto::clicks::ClickResponse, ClickServiceError> {
+        match idempotency_key {
+            Some(key) => {
+                self.idempotency.get_or_process(key, &request, || self.process(request.clone(), click_id_for_key(key))).await
+            }
+            None => self.process(request, Uuid::new_v4()).await,
+        }
     }
 
     #[instrument(
@@ -72,42 +206,44 @@ impl ClickService {
         publish_time = tracing::field::Empty,
         )
     )]
-    pub async fn process_click(
+    async fn process(
         &self,
         request: clickplanet_proto::clicks::ClickRequest,
-    ) -> Result<clickplanet_proto::clicks::ClickResponse, Box<dyn std::error::Error + Send + Sync>> {
+        click_id: Uuid,
+    ) -> Result<clickplanet_proto::clicks::ClickResponse, ClickServiceError> {
+        let timestamp = self.clock.now();
 
-        let click_id = Uuid::new_v4();
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos() as u64;
-
-        let subject = format!("{}{}", CLICK_SUBJECT_PREFIX, request.tile_id);
-
-        let response = clickplanet_proto::clicks::ClickResponse {
+        let mut response = clickplanet_proto::clicks::ClickResponse {
             timestamp_ns: timestamp,
             click_id: click_id.to_string(),
+            outcome: ClickOutcome::Unspecified as i32,
         };
 
         let click_data = clickplanet_proto::clicks::Click {
             tile_id: request.tile_id,
             country_id: request.country_id.clone(),
             timestamp_ns: timestamp,
+            node_id: self.clock.node_id(),
             click_id: click_id.to_string(),
         };
 
-        let mut click_bytes = Vec::new();
-        click_data.encode(&mut click_bytes)?;
-
-        let jetstream = self.jetstream.clone();
+        let outcome_receiver = request.wait_for_apply
+            .then(|| self.outcomes.register(&response.click_id));
 
-        let result = jetstream.publish(subject, click_bytes.into())
-            .await
-            .map_err(|e| ClickServiceError::NatsError(e.to_string()));
-
-        if let Err(e) = result {
-            warn!("Failed to send click to nats channel (service might be shutting down): {:?}", e);
+        // Refused clicks are not applied, the caller is told to retry.
+        // A duplicate is a retry of a click JetStream already stored, it is applied from the stream:
+        // the retry succeeds, without an outcome since this attempt does not apply it.
+        let published = self.publish(&click_data).await;
+        if !matches!(published, Ok(false)) && outcome_receiver.is_some() {
+            self.outcomes.forget(&response.click_id);
+        }
+        match published {
+            Err(e) => return Err(e),
+            Ok(true) => {
+                info!("Click {} was already published, answering the retry", response.click_id);
+                return Ok(response);
+            }
+            Ok(false) => {}
         }
 
         let send_error= self.sender.send(click_data);
@@ -115,6 +251,8 @@ impl ClickService {
             warn!("Failed to send click to in memory channel (service might be shutting down): {:?}", e);
         }
 
+        CLICKS_ACCEPTED.inc();
+
         let publish_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -133,6 +271,16 @@ impl ClickService {
             request.tile_id, request.country_id
         );
 
+        if let Some(receiver) = outcome_receiver {
+            match tokio::time::timeout(APPLY_TIMEOUT, receiver).await {
+                Ok(Ok(outcome)) => response.outcome = outcome as i32,
+                _ => {
+                    warn!("Click {} was not applied within {:?}", response.click_id, APPLY_TIMEOUT);
+                    self.outcomes.forget(&response.click_id);
+                }
+            }
+        }
+
         Ok(response)
     }
 }
\ No newline at end of file
@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use clickplanet_proto::clicks::ClickRequest;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+const MAX_COUNTRY_ID_LENGTH: usize = 16;
+
+static CLICKS_REJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "clickplanet_clicks_rejected_total",
+    "Clicks refused by validation, by error code",
+    &["reason"]
+).unwrap());
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ClickValidationError {
+    #[error("tile_id must not be negative")]
+    NegativeTile,
+    #[error("tile_id must be lower than {tile_count}")]
+    UnknownTile { tile_count: u32 },
+    #[error("country_id must be 1 to {MAX_COUNTRY_ID_LENGTH} lowercase letters, digits or dashes")]
+    MalformedCountry,
+    #[error("country_id is not a known country code")]
+    UnknownCountry,
+}
+
+#[derive(Error, Debug)]
+pub enum ClickValidatorLoadError {
+    #[error("Could not read {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("Could not parse {0}: {1}")]
+    Json(String, serde_json::Error),
+}
+
+impl ClickValidationError {
+    fn code(&self) -> &'static str {
+        match self {
+            ClickValidationError::NegativeTile | ClickValidationError::UnknownTile { .. } => "invalid_tile_id",
+            ClickValidationError::MalformedCountry | ClickValidationError::UnknownCountry => "invalid_country_id",
+        }
+    }
+
+    fn field(&self) -> &'static str {
+        match self {
+            ClickValidationError::NegativeTile | ClickValidationError::UnknownTile { .. } => "tile_id",
+            ClickValidationError::MalformedCountry | ClickValidationError::UnknownCountry => "country_id",
+        }
+    }
+}
+
+impl IntoResponse for ClickValidationError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "error": {
+                "code": self.code(),
+                "field": self.field(),
+                "message": self.to_string(),
+            }
+        });
+
+        (StatusCode::BAD_REQUEST, Json(body)).into_response()
+    }
+}
+
+/// Only the vertex positions matter to count the tiles of the map.
+#[derive(Deserialize)]
+struct CoordinatesFile {
+    positions: Vec<f64>,
+}
+
+/// Rejects clicks on tiles outside the map or for unknown countries before they are published.
+/// Without a map topology or a country list, only the shape of the click is checked.
+#[derive(Debug, Default)]
+pub struct ClickValidator {
+    tile_count: Option<u32>,
+    countries: Option<HashSet<String>>,
+}
+
+impl ClickValidator {
+    pub fn new(tile_count: Option<u32>, countries: Option<HashSet<String>>) -> Self {
+        Self { tile_count, countries }
+    }
+
+    /// Number of tiles of the map described by a coordinates.json file
+    pub fn tile_count_from_coordinates(path: &str) -> Result<u32, ClickValidatorLoadError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| ClickValidatorLoadError::Io(path.to_string(), e))?;
+        let coordinates: CoordinatesFile = serde_json::from_str(&content)
+            .map_err(|e| ClickValidatorLoadError::Json(path.to_string(), e))?;
+
+        Ok((coordinates.positions.len() / 3) as u32)
+    }
+
+    /// Country codes of a country_to_tiles.json file
+    pub fn countries_from_country_tiles(path: &str) -> Result<HashSet<String>, ClickValidatorLoadError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| ClickValidatorLoadError::Io(path.to_string(), e))?;
+        let country_tiles: HashMap<String, serde_json::Value> = serde_json::from_str(&content)
+            .map_err(|e| ClickValidatorLoadError::Json(path.to_string(), e))?;
+
+        Ok(country_tiles.into_keys().collect())
+    }
+
+    pub fn validate(&self, request: &ClickRequest) -> Result<(), ClickValidationError> {
+        let result = self.check(request);
+
+        if let Err(e) = &result {
+            CLICKS_REJECTED.with_label_values(&[e.code()]).inc();
+        }
+
+        result
+    }
+
+    fn check(&self, request: &ClickRequest) -> Result<(), ClickValidationError> {
+        if request.tile_id < 0 {
+            return Err(ClickValidationError::NegativeTile);
+        }
+
+        if let Some(tile_count) = self.tile_count {
+            if request.tile_id as u32 >= tile_count {
+                return Err(ClickValidationError::UnknownTile { tile_count });
+            }
+        }
+
+        let country_id = request.country_id.as_str();
+        let well_formed = !country_id.is_empty() &&
+            country_id.len() <= MAX_COUNTRY_ID_LENGTH &&
+            country_id.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+
+        if !well_formed {
+            return Err(ClickValidationError::MalformedCountry);
+        }
+
+        match &self.countries {
+            Some(countries) if !countries.contains(country_id) => Err(ClickValidationError::UnknownCountry),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn click(tile_id: i32, country_id: &str) -> ClickRequest {
+        ClickRequest {
+            tile_id,
+            country_id: country_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_shape_is_always_checked() {
+        let validator = ClickValidator::default();
+
+        assert_eq!(validator.validate(&click(42, "fr")), Ok(()));
+        assert_eq!(validator.validate(&click(-1, "fr")), Err(ClickValidationError::NegativeTile));
+        assert_eq!(validator.validate(&click(42, "")), Err(ClickValidationError::MalformedCountry));
+        assert_eq!(validator.validate(&click(42, "FR")), Err(ClickValidationError::MalformedCountry));
+        assert_eq!(validator.validate(&click(42, &"a".repeat(500))), Err(ClickValidationError::MalformedCountry));
+    }
+
+    #[test]
+    fn test_topology_and_countries() {
+        let countries = ["fr", "ru"].into_iter().map(String::from).collect();
+        let validator = ClickValidator::new(Some(100), Some(countries));
+
+        assert_eq!(validator.validate(&click(99, "ru")), Ok(()));
+        assert_eq!(validator.validate(&click(100, "ru")), Err(ClickValidationError::UnknownTile { tile_count: 100 }));
+        assert_eq!(validator.validate(&click(99, "es")), Err(ClickValidationError::UnknownCountry));
+    }
+}
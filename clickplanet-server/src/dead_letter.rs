@@ -0,0 +1,220 @@
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use async_nats::jetstream;
+use async_nats::jetstream::context::Publish;
+use async_nats::HeaderMap;
+use bytes::Bytes;
+use clickplanet_proto::clicks::Click;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use prost::Message;
+use thiserror::Error;
+
+use crate::streams::CLICK_SUBJECT_PREFIX;
+
+pub const DLQ_STREAM_NAME: &str = "CLICKS_DLQ";
+const DLQ_SUBJECT_PREFIX: &str = "clicks.dlq.";
+const DLQ_MAX_AGE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+/// Each replica has its own consumers, which can give up on the same message far apart
+const DLQ_DUPLICATE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub const ERROR_HEADER: &str = "Clickplanet-Error";
+pub const CONSUMER_HEADER: &str = "Clickplanet-Consumer";
+pub const SOURCE_SUBJECT_HEADER: &str = "Clickplanet-Source-Subject";
+pub const SOURCE_SEQUENCE_HEADER: &str = "Clickplanet-Source-Sequence";
+pub const DELIVERY_COUNT_HEADER: &str = "Clickplanet-Delivery-Count";
+
+static DEAD_LETTERED: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "clickplanet_dead_lettered_total",
+    "Messages copied to the dead letter stream because they could not be processed",
+    &["consumer"]
+).unwrap());
+
+#[derive(Error, Debug)]
+pub enum DeadLetterError {
+    #[error("Dead letter stream error: {0}")]
+    Stream(String),
+    #[error("No dead-lettered click at sequence {0}")]
+    NotFound(u64),
+    #[error("Dead letter {sequence} comes from {subject}, which is not a click subject")]
+    InvalidSource { sequence: u64, subject: String },
+}
+
+impl From<async_nats::Error> for DeadLetterError {
+    fn from(e: async_nats::Error) -> Self {
+        DeadLetterError::Stream(e.to_string())
+    }
+}
+
+/// Creates the dead letter stream unless it exists. Direct gets let the tooling read any message of it.
+pub async fn get_or_create_dlq_stream(jetstream: &jetstream::Context) -> Result<jetstream::stream::Stream, DeadLetterError> {
+    Ok(jetstream.get_or_create_stream(jetstream::stream::Config {
+        name: DLQ_STREAM_NAME.to_string(),
+        subjects: vec![format!("{}>", DLQ_SUBJECT_PREFIX)],
+        max_age: DLQ_MAX_AGE,
+        duplicate_window: DLQ_DUPLICATE_WINDOW.as_nanos() as i64,
+        allow_direct: true,
+        ..Default::default()
+    }).await?)
+}
+
+/// The tooling only reads the stream the consumers created
+async fn get_dlq_stream(jetstream: &jetstream::Context) -> Result<jetstream::stream::Stream, DeadLetterError> {
+    Ok(jetstream.get_stream(DLQ_STREAM_NAME).await?)
+}
+
+/// Whether JetStream will not deliver the message again after this attempt
+pub fn is_last_delivery(message: &jetstream::Message, max_deliver: i64) -> bool {
+    max_deliver > 0 && message.info().map(|info| info.delivered >= max_deliver).unwrap_or(false)
+}
+
+/// Header values cannot span lines
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Copies the messages a consumer gives up on to the dead letter stream, with why and where they came from
+#[derive(Clone)]
+pub struct DeadLetterQueue {
+    jetstream: Arc<jetstream::Context>,
+    consumer: String,
+}
+
+impl DeadLetterQueue {
+    pub fn new(jetstream: Arc<jetstream::Context>, consumer: &str) -> Self {
+        Self { jetstream, consumer: consumer.to_string() }
+    }
+
+    /// Returns once the dead letter stream stored the message, it can then be acknowledged.
+    /// Replicas dead-lettering the same message are deduplicated on its stream sequence.
+    pub async fn send(&self, message: &jetstream::Message, error: &str) -> Result<(), DeadLetterError> {
+        let (sequence, delivered) = message.info()
+            .map(|info| (info.stream_sequence, info.delivered))
+            .map_err(DeadLetterError::from)?;
+
+        let publish = Publish::build()
+            .payload(message.payload.clone())
+            .header(ERROR_HEADER, header_value(error).as_str())
+            .header(CONSUMER_HEADER, self.consumer.as_str())
+            .header(SOURCE_SUBJECT_HEADER, message.subject.as_str())
+            .header(SOURCE_SEQUENCE_HEADER, sequence.to_string().as_str())
+            .header(DELIVERY_COUNT_HEADER, delivered.to_string().as_str())
+            .message_id(format!("{}-{}", self.consumer, sequence));
+
+        self.jetstream
+            .send_publish(format!("{}{}", DLQ_SUBJECT_PREFIX, self.consumer), publish)
+            .await?
+            .await?;
+
+        DEAD_LETTERED.with_label_values(&[&self.consumer]).inc();
+        Ok(())
+    }
+}
+
+/// A message of the dead letter stream
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub sequence: u64,
+    pub consumer: String,
+    pub error: String,
+    pub source_subject: String,
+    pub source_sequence: Option<u64>,
+    pub delivery_count: Option<i64>,
+    pub payload: Bytes,
+}
+
+impl DeadLetter {
+    /// None unless the message carries the headers written by `DeadLetterQueue::send`
+    fn from_message(sequence: u64, headers: Option<&HeaderMap>, payload: Bytes) -> Option<Self> {
+        let headers = headers?;
+        let header = |name: &str| headers.get(name).map(|value| value.as_str().to_string());
+
+        Some(Self {
+            sequence,
+            consumer: header(CONSUMER_HEADER).unwrap_or_default(),
+            error: header(ERROR_HEADER).unwrap_or_default(),
+            source_subject: header(SOURCE_SUBJECT_HEADER)?,
+            source_sequence: header(SOURCE_SEQUENCE_HEADER).and_then(|value| value.parse().ok()),
+            delivery_count: header(DELIVERY_COUNT_HEADER).and_then(|value| value.parse().ok()),
+            payload,
+        })
+    }
+
+    pub fn click(&self) -> Result<Click, prost::DecodeError> {
+        Click::decode(self.payload.clone())
+    }
+}
+
+/// Dead letters from `from_sequence` on, oldest first
+pub async fn list(jetstream: &jetstream::Context, from_sequence: u64, limit: usize) -> Result<Vec<DeadLetter>, DeadLetterError> {
+    let mut stream = get_dlq_stream(jetstream).await?;
+    let state = stream.info().await?.state;
+
+    let mut dead_letters = Vec::new();
+    for sequence in from_sequence.max(state.first_sequence)..=state.last_sequence {
+        if dead_letters.len() >= limit {
+            break;
+        }
+        // Sequences of re-injected clicks are gone
+        if let Ok(dead_letter) = get_from(&stream, sequence).await {
+            dead_letters.push(dead_letter);
+        }
+    }
+
+    Ok(dead_letters)
+}
+
+pub async fn get(jetstream: &jetstream::Context, sequence: u64) -> Result<DeadLetter, DeadLetterError> {
+    let stream = get_dlq_stream(jetstream).await?;
+    get_from(&stream, sequence).await
+}
+
+async fn get_from(stream: &jetstream::stream::Stream, sequence: u64) -> Result<DeadLetter, DeadLetterError> {
+    let message = stream.direct_get(sequence).await.map_err(|_| DeadLetterError::NotFound(sequence))?;
+
+    DeadLetter::from_message(sequence, message.headers.as_ref(), message.payload.clone())
+        .ok_or(DeadLetterError::NotFound(sequence))
+}
+
+/// Publishes the click again on its original subject, then drops it from the dead letter stream.
+/// It goes through the consumers like a new click, and is ignored if a newer one owns the tile by then.
+pub async fn reinject(jetstream: &jetstream::Context, sequence: u64) -> Result<DeadLetter, DeadLetterError> {
+    let stream = get_dlq_stream(jetstream).await?;
+    let dead_letter = get_from(&stream, sequence).await?;
+
+    if !dead_letter.source_subject.starts_with(CLICK_SUBJECT_PREFIX) {
+        return Err(DeadLetterError::InvalidSource { sequence, subject: dead_letter.source_subject });
+    }
+
+    jetstream
+        .publish(dead_letter.source_subject.clone(), dead_letter.payload.clone())
+        .await?
+        .await?;
+    stream.delete_message(sequence).await?;
+
+    Ok(dead_letter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_letters_are_read_from_their_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ERROR_HEADER, header_value("invalid wire type\nat offset 3").as_str());
+        headers.insert(CONSUMER_HEADER, "tile-state-processor");
+        headers.insert(SOURCE_SUBJECT_HEADER, "clicks.tile.42");
+        headers.insert(SOURCE_SEQUENCE_HEADER, "1234");
+        headers.insert(DELIVERY_COUNT_HEADER, "3");
+
+        let dead_letter = DeadLetter::from_message(7, Some(&headers), Bytes::from_static(b"\xff")).unwrap();
+        assert_eq!(dead_letter.error, "invalid wire type at offset 3");
+        assert_eq!(dead_letter.source_subject, "clicks.tile.42");
+        assert_eq!(dead_letter.source_sequence, Some(1234));
+        assert_eq!(dead_letter.delivery_count, Some(3));
+        assert!(dead_letter.click().is_err());
+
+        assert!(DeadLetter::from_message(8, Some(&HeaderMap::new()), Bytes::new()).is_none());
+        assert!(DeadLetter::from_message(9, None, Bytes::new()).is_none());
+    }
+}
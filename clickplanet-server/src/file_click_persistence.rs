@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use clickplanet_proto::clicks::OwnershipState;
+use prost::Message;
+use tracing::{debug, error, info};
+
+use crate::click_persistence::{ClickRepository, ClickRepositoryError};
+use crate::shutdown::Shutdown;
+
+/// Protobuf-encoded `OwnershipState` on local disk, used as cold storage in embedded mode.
+#[derive(Clone, Debug)]
+pub struct FileSnapshotStore {
+    path: PathBuf,
+}
+
+impl FileSnapshotStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub async fn load(&self) -> Result<OwnershipState, ClickRepositoryError> {
+        let bytes = match tokio::fs::read(&self.path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No snapshot at {:?}, starting from an empty map", self.path);
+                return Ok(OwnershipState { ownerships: Vec::new() });
+            }
+            Err(e) => return Err(ClickRepositoryError::StorageError(e.to_string())),
+        };
+
+        OwnershipState::decode(bytes.as_slice())
+            .map_err(|e| ClickRepositoryError::InvalidDataError(e.to_string()))
+    }
+
+    pub async fn save(&self, state: &OwnershipState) -> Result<(), ClickRepositoryError> {
+        // Written and synced next to the target then renamed, so a crash never leaves a truncated snapshot
+        let path = self.path.clone();
+        let bytes = state.encode_to_vec();
+
+        tokio::task::spawn_blocking(move || {
+            let tmp_path = path.with_extension("tmp");
+            {
+                let mut file = File::create(&tmp_path)?;
+                file.write_all(&bytes)?;
+                file.sync_all()?;
+            }
+            std::fs::rename(&tmp_path, &path)
+        })
+            .await
+            .map_err(|e| ClickRepositoryError::StorageError(e.to_string()))?
+            .map_err(|e| ClickRepositoryError::StorageError(e.to_string()))?;
+
+        debug!("Saved {} ownerships to {:?}", state.ownerships.len(), self.path);
+        Ok(())
+    }
+
+    pub async fn save_from(&self, repository: &dyn ClickRepository) -> Result<(), ClickRepositoryError> {
+        let state = repository.get_ownerships().await?;
+        self.save(&state).await
+    }
+
+    /// Saves every `interval` until the shutdown is triggered, a save in progress is completed first
+    pub async fn run_periodic(self, repository: Arc<dyn ClickRepository>, interval: Duration, shutdown: Shutdown) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.wait() => return,
+            }
+
+            if let Err(e) = self.save_from(repository.as_ref()).await {
+                error!("Failed to write snapshot to {:?}: {:?}", self.path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickplanet_proto::clicks::Ownership;
+
+    #[tokio::test]
+    async fn test_save_and_load_roundtrip() {
+        let path = std::env::temp_dir().join(format!("clickplanet-snapshot-{}.bin", uuid::Uuid::new_v4()));
+        let store = FileSnapshotStore::new(&path);
+
+        assert!(store.load().await.unwrap().ownerships.is_empty());
+
+        let state = OwnershipState {
+            ownerships: vec![
+                Ownership { tile_id: 1, country_id: "fr".to_string(), timestamp_ns: 10, node_id: 0 },
+                Ownership { tile_id: 2, country_id: "ru".to_string(), timestamp_ns: 20, node_id: 0 },
+            ],
+        };
+        store.save(&state).await.unwrap();
+
+        assert_eq!(store.load().await.unwrap(), state);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use std::pin::Pin;
+use clickplanet_proto::clicks::click_planet_server::ClickPlanet;
+use clickplanet_proto::clicks::{
+    ClickRequest, ClickResponse, GetOwnershipsRequest, GetTileRequest, GetTileResponse,
+    LeaderboardRequest, LeaderboardResponse, OwnershipState, UpdateNotification, WatchUpdatesRequest,
+};
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Request, Response, Status};
+use tracing::error;
+
+use crate::click_persistence::ClickRepository;
+use crate::click_service::ClickServiceError;
+use crate::idempotency::{validate_key, IDEMPOTENCY_KEY_HEADER};
+use crate::notification_log::{Replay, BROADCAST_DROPPED};
+use crate::{leaderboard_response, AppState, CLICK_TIMEOUT};
+
+const DEFAULT_OWNERSHIPS_CHUNK_SIZE: usize = 10000;
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// The RPC and websocket endpoints of the click server, as a `clicks.v1.ClickPlanet` gRPC service.
+pub struct ClickPlanetGrpcService<T: ClickRepository + Send + Sync> {
+    state: AppState<T>,
+}
+
+impl<T: ClickRepository + Send + Sync> ClickPlanetGrpcService<T> {
+    pub fn new(state: AppState<T>) -> Self {
+        Self { state }
+    }
+}
+
+#[tonic::async_trait]
+impl<T: ClickRepository + Send + Sync + 'static> ClickPlanet for ClickPlanetGrpcService<T> {
+    async fn click(&self, request: Request<ClickRequest>) -> Result<Response<ClickResponse>, Status> {
+        let idempotency_key = match request.metadata().get(IDEMPOTENCY_KEY_HEADER) {
+            Some(value) => {
+                let key = value.to_str()
+                    .map_err(|_| Status::invalid_argument("The idempotency key must be visible ASCII"))?;
+                validate_key(key).map_err(|e| Status::invalid_argument(e.to_string()))?;
+                Some(key.to_string())
+            }
+            None => None,
+        };
+
+        let request = request.into_inner();
+        self.state.click_validator.validate(&request)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let response = tokio::time::timeout(
+            CLICK_TIMEOUT,
+            self.state.click_service.process_click(request, idempotency_key.as_deref()),
+        )
+            .await
+            .map_err(|e| {
+                error!("Timeout error while clicking: {:?}", e);
+                Status::deadline_exceeded("The click could not be processed in time")
+            })?
+            .map_err(|e| match e {
+                ClickServiceError::Unavailable(reason) => Status::unavailable(reason),
+                ClickServiceError::Idempotency(e) => Status::failed_precondition(e.to_string()),
+                e => {
+                    error!("Error while processing click: {:?}", e);
+                    Status::internal("Could not process the click")
+                }
+            })?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn get_tile(&self, request: Request<GetTileRequest>) -> Result<Response<GetTileResponse>, Status> {
+        let ownership = self.state.click_repository.get_tile(request.into_inner().tile_id)
+            .await
+            .map_err(|e| {
+                error!("Error while processing get_tile: {:?}", e);
+                Status::internal("Could not read the tile")
+            })?;
+
+        Ok(Response::new(GetTileResponse { ownership }))
+    }
+
+    type GetOwnershipsStream = ResponseStream<OwnershipState>;
+
+    async fn get_ownerships(&self, request: Request<GetOwnershipsRequest>) -> Result<Response<Self::GetOwnershipsStream>, Status> {
+        let chunk_size = match request.into_inner().chunk_size as usize {
+            0 => DEFAULT_OWNERSHIPS_CHUNK_SIZE,
+            chunk_size => chunk_size,
+        };
+
+        let ownership_state = self.state.click_repository.get_ownerships()
+            .await
+            .map_err(|e| {
+                error!("Error while processing get_ownerships: {:?}", e);
+                Status::internal("Could not read the ownerships")
+            })?;
+
+        let chunks: Vec<OwnershipState> = ownership_state.ownerships
+            .chunks(chunk_size)
+            .map(|chunk| OwnershipState { ownerships: chunk.to_vec() })
+            .collect();
+
+        Ok(Response::new(stream::iter(chunks).map(Ok).boxed()))
+    }
+
+    async fn get_leaderboard(&self, _request: Request<LeaderboardRequest>) -> Result<Response<LeaderboardResponse>, Status> {
+        let scores = self.state.leaderboard_repo.leaderboard()
+            .await
+            .map_err(|e| {
+                error!("Error while fetching leaderboard: {:?}", e);
+                Status::internal("Could not compute the leaderboard")
+            })?;
+
+        Ok(Response::new(leaderboard_response(scores)))
+    }
+
+    type WatchUpdatesStream = ResponseStream<UpdateNotification>;
+
+    async fn watch_updates(&self, request: Request<WatchUpdatesRequest>) -> Result<Response<Self::WatchUpdatesStream>, Status> {
+        let request = request.into_inner();
+
+        // Subscribe before reading the log so nothing falls between the replay and the live stream
+        let receiver = self.state.notification_log.subscribe();
+
+        let missed = match request.last_sequence {
+            0 => Vec::new(),
+            last_sequence => match self.state.notification_log.replay_after(request.epoch, last_sequence) {
+                Replay::Updates(missed) => missed,
+                Replay::Resync => return Err(Status::out_of_range("Cannot resume from this position, reload the ownerships")),
+            },
+        };
+
+        let replayed_up_to = missed.last().map(|notification| notification.sequence).unwrap_or(0);
+
+        let live = stream::unfold(Some(receiver), move |receiver| async move {
+            let mut receiver = receiver?;
+            loop {
+                match receiver.recv().await {
+                    Ok(notification) if notification.sequence <= replayed_up_to => continue,
+                    Ok(notification) => return Some((Ok(notification), Some(receiver))),
+                    Err(RecvError::Lagged(missed_updates)) => {
+                        BROADCAST_DROPPED.with_label_values(&["updates", "grpc"]).inc_by(missed_updates);
+                        let status = Status::data_loss("Updates were missed, resume from the last notification received");
+                        return Some((Err(status), None));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        let shutdown = self.state.shutdown.clone();
+        let updates = stream::iter(missed.into_iter().map(Ok)).chain(live)
+            .take_until(async move { shutdown.wait().await });
+
+        Ok(Response::new(updates.boxed()))
+    }
+}
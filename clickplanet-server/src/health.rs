@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use async_nats::jetstream;
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::future::join_all;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::redis_click_persistence::RedisClickRepository;
+
+/// A dependency slower than this to answer is reported as unavailable
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Something the process needs before it can serve traffic.
+#[async_trait]
+pub trait ReadinessCheck: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Why the dependency cannot be used, if it cannot
+    async fn check(&self) -> Result<(), String>;
+}
+
+/// JetStream answers account requests
+pub struct NatsCheck(pub Arc<jetstream::Context>);
+
+#[async_trait]
+impl ReadinessCheck for NatsCheck {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        self.0.query_account()
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Redis answers PING
+pub struct RedisCheck(pub Arc<RedisClickRepository>);
+
+#[async_trait]
+impl ReadinessCheck for RedisCheck {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        self.0.ping()
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// State the process maintains itself, such as a consumer being running.
+pub struct ConditionCheck<F> {
+    name: &'static str,
+    condition: F,
+    failure: &'static str,
+}
+
+impl<F: Fn() -> bool + Send + Sync> ConditionCheck<F> {
+    pub fn new(name: &'static str, condition: F, failure: &'static str) -> Self {
+        Self { name, condition, failure }
+    }
+}
+
+#[async_trait]
+impl<F: Fn() -> bool + Send + Sync> ReadinessCheck for ConditionCheck<F> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        if (self.condition)() {
+            Ok(())
+        } else {
+            Err(self.failure.to_string())
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum CheckStatus {
+    Ok,
+    Error { error: String },
+}
+
+#[derive(Clone, Default)]
+pub struct Readiness {
+    checks: Vec<Arc<dyn ReadinessCheck>>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, check: impl ReadinessCheck + 'static) -> Self {
+        self.checks.push(Arc::new(check));
+        self
+    }
+
+    async fn statuses(&self) -> BTreeMap<&'static str, CheckStatus> {
+        let checks = self.checks.iter().map(|check| async move {
+            let status = match tokio::time::timeout(CHECK_TIMEOUT, check.check()).await {
+                Ok(Ok(())) => CheckStatus::Ok,
+                Ok(Err(error)) => CheckStatus::Error { error },
+                Err(_) => CheckStatus::Error { error: format!("no answer within {:?}", CHECK_TIMEOUT) },
+            };
+            (check.name(), status)
+        });
+
+        join_all(checks).await.into_iter().collect()
+    }
+}
+
+/// The process is up, whatever the state of its dependencies
+async fn handle_healthz() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+/// 200 when every check passes, 503 otherwise, with the status of each check
+async fn handle_readyz(State(readiness): State<Readiness>) -> Response {
+    let checks = readiness.statuses().await;
+    let ready = checks.values().all(|status| *status == CheckStatus::Ok);
+
+    let body = json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "checks": checks,
+    });
+
+    let code = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(body)).into_response()
+}
+
+/// `/healthz` for liveness and `/readyz` for readiness
+pub fn health_routes(readiness: Readiness) -> Router {
+    Router::new()
+        .route("/healthz", get(handle_healthz))
+        .route("/readyz", get(handle_readyz))
+        .with_state(readiness)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn test_readiness_reports_every_check() {
+        let loaded = Arc::new(AtomicBool::new(false));
+        let loaded_clone = loaded.clone();
+        let readiness = Readiness::new()
+            .with(ConditionCheck::new("consumer", || true, "not running"))
+            .with(ConditionCheck::new("map", move || loaded_clone.load(Ordering::Relaxed), "still loading"));
+
+        let response = handle_readyz(State(readiness.clone())).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({
+            "status": "not_ready",
+            "checks": {
+                "consumer": { "status": "ok" },
+                "map": { "status": "error", "error": "still loading" },
+            }
+        }));
+
+        loaded.store(true, Ordering::Relaxed);
+        assert_eq!(handle_readyz(State(readiness)).await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use prometheus::{register_int_counter, IntCounter};
+use tracing::warn;
+
+/// Timestamps further ahead of the wall clock come from a broken clock and are not followed
+const MAX_DRIFT: Duration = Duration::from_secs(60);
+
+static CLOCK_DRIFT_REJECTED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "clickplanet_clock_drift_rejected_total",
+    "Timestamps of other servers too far ahead of the local clock to be followed"
+).unwrap());
+
+/// Hybrid logical clock in nanoseconds. It follows the wall clock, but never goes backwards, never gives
+/// the same timestamp twice and stays ahead of the clicks seen from other servers, so that a click taken
+/// after another one was seen is ordered after it whatever the clock skew between the servers.
+pub struct HybridClock {
+    node_id: u32,
+    last: AtomicU64,
+}
+
+fn wall_clock_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+impl HybridClock {
+    pub fn new(node_id: u32) -> Self {
+        Self { node_id, last: AtomicU64::new(0) }
+    }
+
+    /// Breaks the ties between clicks of different servers with the same timestamp
+    pub fn node_id(&self) -> u32 {
+        self.node_id
+    }
+
+    pub fn now(&self) -> u64 {
+        let wall = wall_clock_ns();
+        let mut last = self.last.load(Ordering::Acquire);
+
+        loop {
+            let next = wall.max(last + 1);
+            match self.last.compare_exchange_weak(last, next, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return next,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    /// Moves the clock past a timestamp taken by another server
+    pub fn observe(&self, timestamp_ns: u64) {
+        let limit = wall_clock_ns().saturating_add(MAX_DRIFT.as_nanos() as u64);
+        if timestamp_ns > limit {
+            CLOCK_DRIFT_REJECTED.inc();
+            warn!("Not following timestamp {}, more than {:?} ahead of the local clock", timestamp_ns, MAX_DRIFT);
+            return;
+        }
+
+        self.last.fetch_max(timestamp_ns, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_is_strictly_increasing_and_follows_other_servers() {
+        let clock = HybridClock::new(1);
+
+        let first = clock.now();
+        assert!(clock.now() > first);
+
+        let ahead = wall_clock_ns() + Duration::from_secs(5).as_nanos() as u64;
+        clock.observe(ahead);
+        assert!(clock.now() > ahead);
+
+        let broken = wall_clock_ns() + Duration::from_secs(3600).as_nanos() as u64;
+        clock.observe(broken);
+        assert!(clock.now() < broken);
+    }
+}
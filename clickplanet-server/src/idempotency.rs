@@ -0,0 +1,182 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use clickplanet_proto::clicks::{ClickRequest, ClickResponse};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// How long a key is remembered, also the duplicate window of the click stream
+pub const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(120);
+
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Namespace of the click ids derived from idempotency keys
+const CLICK_ID_NAMESPACE: Uuid = Uuid::from_u128(0x6b1c_5d2e_8f3a_4c71_9e0b_2a4d_6f8c_1e35);
+
+#[derive(Error, Debug, PartialEq)]
+pub enum IdempotencyError {
+    #[error("The idempotency key was already used for another click")]
+    KeyReused,
+    #[error("The idempotency key must be 1 to {MAX_KEY_LENGTH} characters")]
+    InvalidKey,
+}
+
+/// Shared by the HTTP and gRPC click endpoints, the header value is visible ASCII already
+pub fn validate_key(key: &str) -> Result<(), IdempotencyError> {
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(IdempotencyError::InvalidKey);
+    }
+    Ok(())
+}
+
+/// Optional `Idempotency-Key` header of a click, rejected with 400 unless 1 to 255 visible ASCII characters.
+pub struct IdempotencyKey(pub Option<String>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IdempotencyKey {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(IDEMPOTENCY_KEY_HEADER) else {
+            return Ok(IdempotencyKey(None));
+        };
+
+        let key = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?;
+        validate_key(key).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        Ok(IdempotencyKey(Some(key.to_string())))
+    }
+}
+
+/// Every retry of a click carries the same id, whichever replica it reaches,
+/// so JetStream drops the repeats as duplicate messages.
+pub fn click_id_for_key(key: &str) -> Uuid {
+    Uuid::new_v5(&CLICK_ID_NAMESPACE, key.as_bytes())
+}
+
+struct Entry {
+    tile_id: i32,
+    country_id: String,
+    response: Arc<OnceCell<ClickResponse>>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    // Insertion order, to forget the keys once the window is over
+    expiries: VecDeque<(Instant, String)>,
+}
+
+/// Responses of the clicks sent with an idempotency key, kept for the duplicate window.
+/// Retries get the response of the original click, and wait for it while it is processed.
+pub struct IdempotencyCache {
+    entries: Mutex<Entries>,
+    window: Duration,
+}
+
+impl IdempotencyCache {
+    pub fn new(window: Duration) -> Self {
+        Self { entries: Mutex::new(Entries::default()), window }
+    }
+
+    fn slot(&self, key: &str, request: &ClickRequest) -> Result<Arc<OnceCell<ClickResponse>>, IdempotencyError> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        while let Some((expiry, _)) = entries.expiries.front() {
+            if *expiry > now {
+                break;
+            }
+            if let Some((_, expired_key)) = entries.expiries.pop_front() {
+                entries.by_key.remove(&expired_key);
+            }
+        }
+
+        if let Some(entry) = entries.by_key.get(key) {
+            if entry.tile_id != request.tile_id || entry.country_id != request.country_id {
+                return Err(IdempotencyError::KeyReused);
+            }
+            return Ok(entry.response.clone());
+        }
+
+        let response = Arc::new(OnceCell::new());
+        entries.by_key.insert(key.to_string(), Entry {
+            tile_id: request.tile_id,
+            country_id: request.country_id.clone(),
+            response: response.clone(),
+        });
+        entries.expiries.push_back((now + self.window, key.to_string()));
+
+        Ok(response)
+    }
+
+    /// Processes the click unless it was already, a failed attempt leaves the next retry free to process it.
+    pub async fn get_or_process<F, Fut, E>(&self, key: &str, request: &ClickRequest, process: F) -> Result<ClickResponse, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<ClickResponse, E>>,
+        E: From<IdempotencyError>,
+    {
+        let response = self.slot(key, request)?;
+
+        response.get_or_try_init(process).await.cloned()
+    }
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new(IDEMPOTENCY_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn request(tile_id: i32) -> ClickRequest {
+        ClickRequest { tile_id, country_id: "fr".to_string(), wait_for_apply: false }
+    }
+
+    #[tokio::test]
+    async fn test_retries_get_the_original_response() {
+        let cache = IdempotencyCache::default();
+        let processed = AtomicUsize::new(0);
+
+        let process = |click_id: &str| {
+            let click_id = click_id.to_string();
+            let processed = &processed;
+            move || async move {
+                processed.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, IdempotencyError>(ClickResponse { timestamp_ns: 1, click_id, outcome: 0 })
+            }
+        };
+
+        let first = cache.get_or_process("key", &request(1), process("first")).await.unwrap();
+        let retry = cache.get_or_process("key", &request(1), process("retry")).await.unwrap();
+
+        assert_eq!(retry, first);
+        assert_eq!(processed.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            cache.get_or_process("key", &request(2), process("other")).await,
+            Err(IdempotencyError::KeyReused),
+        );
+        assert_eq!(click_id_for_key("key"), click_id_for_key("key"));
+    }
+
+    #[test]
+    fn test_keys_are_1_to_255_characters() {
+        assert_eq!(validate_key("k"), Ok(()));
+        assert_eq!(validate_key(&"k".repeat(MAX_KEY_LENGTH)), Ok(()));
+        assert_eq!(validate_key(""), Err(IdempotencyError::InvalidKey));
+        assert_eq!(validate_key(&"k".repeat(MAX_KEY_LENGTH + 1)), Err(IdempotencyError::InvalidKey));
+    }
+}
@@ -1,21 +1,31 @@
-use crate::click_persistence::{ClickRepository, ClickRepositoryError, LeaderboardError, LeaderboardMaintainer, LeaderboardRepository};
+use crate::click_persistence::{supersedes, ClickRepository, ClickRepositoryError, LeaderboardError, LeaderboardMaintainer, LeaderboardRepository, OwnershipChanges};
 use async_trait::async_trait;
 use clickplanet_proto::clicks::{Click, Ownership, OwnershipState};
 use papaya::{HashMap as PapayaMap, HashMapRef, HashSet, LocalGuard, Operation};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::RandomState;
-use std::sync::Arc;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Clone)]
 pub struct TileData {
     pub country_id: String,
     pub timestamp_ns: u64,
+    pub node_id: u32,
+    /// Version of the map once this ownership was applied
+    pub sequence: u64,
 }
 
 #[derive(Clone)]
 pub struct PapayaClickRepository {
     tiles: Arc<PapayaMap<u32, TileData>>,
     country_tiles: Arc<PapayaMap<String, Arc<HashSet<u32>>>>,
+    // Tile id of the last change of every tile, by its sequence. Writers hold it while they apply
+    // a click, so sequences follow the apply order whatever the timestamps of the clicks.
+    changes: Arc<Mutex<BTreeMap<u64, u32>>>,
+    // Incremented on every ownership change, the sequence of the last one
+    version: Arc<AtomicU64>,
 }
 
 impl PapayaClickRepository {
@@ -23,14 +33,20 @@ impl PapayaClickRepository {
         Self {
             tiles: Arc::new(PapayaMap::new()),
             country_tiles: Arc::new(PapayaMap::new()),
+            changes: Arc::new(Mutex::new(BTreeMap::new())),
+            version: Arc::new(AtomicU64::new(0)),
         }
     }
 
     pub async fn populate_with(repository: Arc<dyn ClickRepository>) -> Result<Self, ClickRepositoryError> {
-        let papaya= Self::new();
-
         let ownership_state: OwnershipState = repository.get_ownerships().await?;
 
+        Self::populate_from_state(ownership_state).await
+    }
+
+    pub async fn populate_from_state(ownership_state: OwnershipState) -> Result<Self, ClickRepositoryError> {
+        let papaya= Self::new();
+
         for ownership in ownership_state.ownerships {
             let tile_id = ownership.tile_id;
             papaya.save_click(tile_id, &Click{
@@ -38,6 +54,7 @@ impl PapayaClickRepository {
                 country_id: ownership.country_id.clone(),
                 timestamp_ns: ownership.timestamp_ns,
                 click_id: "".to_string(),
+                node_id: ownership.node_id,
             }).await?;
 
             papaya.update_country_index(tile_id, &ownership.country_id, None).await;
@@ -46,6 +63,16 @@ impl PapayaClickRepository {
         Ok(papaya)
     }
 
+    /// Number of tiles owned by any country
+    pub fn tile_count(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Changes whenever an ownership changes, tells readers whether what they built from the map is stale
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
     fn new_tiles(tile_id: u32) -> Arc<HashSet<u32>> {
         let cloned_set = HashSet::new().clone();
 
@@ -72,6 +99,7 @@ impl ClickRepository for PapayaClickRepository {
             tile_id: tile_id as u32,
             country_id: data.country_id.clone(),
             timestamp_ns: data.timestamp_ns,
+            node_id: data.node_id,
         }))
     }
 
@@ -84,6 +112,7 @@ impl ClickRepository for PapayaClickRepository {
                 tile_id: *tile_id as u32,
                 country_id: v.country_id.clone(),
                 timestamp_ns: v.timestamp_ns,
+                node_id: v.node_id,
             })
         });
 
@@ -104,6 +133,7 @@ impl ClickRepository for PapayaClickRepository {
                     tile_id: *k as u32,
                     country_id: v.country_id.clone(),
                     timestamp_ns: v.timestamp_ns,
+                    node_id: v.node_id,
                 });
             }
         });
@@ -111,8 +141,33 @@ impl ClickRepository for PapayaClickRepository {
         Ok(OwnershipState { ownerships })
     }
 
+    async fn get_ownerships_since(
+        &self,
+        since_sequence: u64,
+        limit: usize,
+    ) -> Result<OwnershipChanges, ClickRepositoryError> {
+        let tiles = self.tiles.pin();
+        let changes = self.changes.lock().unwrap();
+
+        let mut page = OwnershipChanges { ownerships: Vec::new(), cursor: since_sequence };
+        for (&sequence, &tile_id) in changes.range((Bound::Excluded(since_sequence), Bound::Unbounded)).take(limit) {
+            if let Some(data) = tiles.get(&tile_id) {
+                page.ownerships.push(Ownership {
+                    tile_id,
+                    country_id: data.country_id.clone(),
+                    timestamp_ns: data.timestamp_ns,
+                    node_id: data.node_id,
+                });
+            }
+            page.cursor = sequence;
+        }
+
+        Ok(page)
+    }
+
     async fn save_click(&self, tile_id: u32, click: &Click) -> Result<Option<Ownership>, ClickRepositoryError> {
         let map_ref = self.tiles.pin();
+        let mut changes = self.changes.lock().unwrap();
 
         let tile_data = map_ref.get(&tile_id);
 
@@ -120,18 +175,28 @@ impl ClickRepository for PapayaClickRepository {
             tile_id,
             country_id: data.country_id.clone(),
             timestamp_ns: data.timestamp_ns,
+            node_id: data.node_id,
         });
 
-        if let Some(current_data) = tile_data {
-            if click.timestamp_ns <= current_data.timestamp_ns {
+        if let Some(previous) = &previous_ownership {
+            if !supersedes(click, previous) {
                 return Ok(previous_ownership);
             }
         }
 
+        if let Some(data) = tile_data {
+            changes.remove(&data.sequence);
+        }
+
+        let sequence = self.version.load(Ordering::Acquire) + 1;
         map_ref.insert(tile_id, TileData {
             country_id: click.country_id.clone(),
             timestamp_ns: click.timestamp_ns,
+            node_id: click.node_id,
+            sequence,
         });
+        changes.insert(sequence, tile_id);
+        self.version.store(sequence, Ordering::Release);
 
         Ok(previous_ownership)
     }
@@ -225,6 +290,7 @@ mod tests {
                     click_id: "".to_string(),
                     country_id: format!("COUNTRY{}", i % 5),
                     timestamp_ns: base_time + i as u64,
+                    node_id: 0,
                 };
                 repo.save_click(tile_id, &click).await
             });
@@ -255,6 +321,7 @@ mod tests {
                     country_id: format!("COUNTRY{}", i % 2),
                     timestamp_ns: (10 + i * 10) as u64,
                     click_id: Uuid::new_v4().to_string(),
+                    node_id: 0,
                 };
 
                 println!("Processing click: {:?}", click);
@@ -290,6 +357,62 @@ mod tests {
         assert_eq!(leaderboard, expected_map);
         assert_eq!(score0 + score1 + score2, 10);
     }
+
+    #[tokio::test]
+    async fn test_ownerships_since() {
+        let repo = PapayaClickRepository::new();
+        let click = |tile_id: u32, country_id: &str, timestamp_ns: u64| Click {
+            tile_id: tile_id as i32,
+            country_id: country_id.to_string(),
+            timestamp_ns,
+            click_id: Uuid::new_v4().to_string(),
+            node_id: 0,
+        };
+
+        for (tile_id, timestamp_ns) in [(1, 10), (2, 20), (3, 20), (4, 30), (5, 40)] {
+            repo.save_click(tile_id, &click(tile_id, "COUNTRY0", timestamp_ns)).await.unwrap();
+        }
+
+        // Tile 1 changes again, it must move to the end
+        repo.save_click(1, &click(1, "COUNTRY1", 50)).await.unwrap();
+
+        let tile_ids = |changes: &OwnershipChanges| changes.ownerships.iter().map(|o| o.tile_id).collect::<Vec<_>>();
+
+        let page = repo.get_ownerships_since(0, 2).await.unwrap();
+        assert_eq!((tile_ids(&page), page.cursor), (vec![2, 3], 3));
+
+        let page = repo.get_ownerships_since(page.cursor, 2).await.unwrap();
+        assert_eq!((tile_ids(&page), page.cursor), (vec![4, 5], 5));
+
+        // Applied after the previous page although older than everything returned so far
+        repo.save_click(6, &click(6, "COUNTRY1", 5)).await.unwrap();
+
+        let page = repo.get_ownerships_since(page.cursor, 2).await.unwrap();
+        assert_eq!((tile_ids(&page), page.cursor), (vec![1, 6], 7));
+        assert_eq!(page.ownerships[0].country_id, "COUNTRY1");
+
+        let page = repo.get_ownerships_since(page.cursor, 2).await.unwrap();
+        assert_eq!((tile_ids(&page), page.cursor), (vec![], 7));
+    }
+
+    #[tokio::test]
+    async fn test_equal_timestamps_are_ordered_by_node() {
+        let repo = PapayaClickRepository::new();
+        let click = |country_id: &str, node_id: u32| Click {
+            tile_id: 1,
+            country_id: country_id.to_string(),
+            timestamp_ns: 100,
+            click_id: Uuid::new_v4().to_string(),
+            node_id,
+        };
+
+        repo.save_click(1, &click("COUNTRY0", 2)).await.unwrap();
+        repo.save_click(1, &click("COUNTRY1", 1)).await.unwrap();
+        assert_eq!(repo.get_tile(1).await.unwrap().unwrap().country_id, "COUNTRY0");
+
+        repo.save_click(1, &click("COUNTRY2", 3)).await.unwrap();
+        assert_eq!(repo.get_tile(1).await.unwrap().unwrap().country_id, "COUNTRY2");
+    }
 }
 
 
@@ -2,47 +2,103 @@ use async_nats::{jetstream};
 use clickplanet_proto::clicks::{Click, UpdateNotification};
 use futures::{future, StreamExt};
 use prost::Message;
-use std::sync::Arc;
-use tracing::{error, info};
-use crate::click_persistence::{ClickRepository, LeaderboardRepository};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use prometheus::{exponential_buckets, register_histogram, register_int_counter, Histogram, IntCounter};
+use tracing::{debug, error, info};
+use crate::click_persistence::{supersedes, ClickRepository, LeaderboardRepository};
 use crate::redis_click_persistence::{RedisClickRepository};
-use crate::nats_commons;
+use clickplanet_server::dead_letter::{get_or_create_dlq_stream, is_last_delivery, DeadLetterQueue};
+use clickplanet_server::streams::{CLICK_SUBJECT_PREFIX, PERSISTER_CONSUMER_NAME};
+use crate::shutdown::Shutdown;
 use crate::nats_commons::{get_stream, ConsumerConfig, PollingConsumerError};
 
+static PERSIST_SECONDS: LazyLock<Histogram> = LazyLock::new(|| register_histogram!(
+    "clickplanet_persister_processing_duration_seconds",
+    "Time to write a batch of clicks to Redis and acknowledge it"
+).unwrap());
 
-const CONSUMER_NAME: &'static str = "tile-state-processor";
+static BATCH_SIZE: LazyLock<Histogram> = LazyLock::new(|| register_histogram!(
+    "clickplanet_persister_batch_size",
+    "Clicks received together and written to Redis in one round trip",
+    exponential_buckets(1.0, 2.0, 12).unwrap()
+).unwrap());
+
+static COALESCED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "clickplanet_persister_coalesced_total",
+    "Clicks not written because a newer click on the same tile was in the same batch"
+).unwrap());
+
+static REDELIVERIES: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "clickplanet_persister_redeliveries_total",
+    "Clicks delivered again by JetStream after a missing acknowledgment"
+).unwrap());
+
+#[derive(Clone, Debug)]
+pub struct BatchConfig {
+    /// Most messages pulled and written together
+    pub size: usize,
+    /// Longest wait for a batch to fill before it is written anyway
+    pub flush_interval: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            size: 500,
+            flush_interval: Duration::from_millis(50),
+        }
+    }
+}
 
 pub struct ClickConsumer {
     jetstream: Arc<jetstream::Context>,
     consumer_config: ConsumerConfig,
+    batch_config: BatchConfig,
     click_repository: Arc<dyn ClickRepository>,
+    dead_letters: DeadLetterQueue,
+    running: AtomicBool,
 }
 
 impl ClickConsumer {
-    pub async fn new(nats_url: &str, consumer_config: Option<ConsumerConfig>,
-                     redis_click_repository: RedisClickRepository) -> Result<Self, PollingConsumerError> {
+    pub async fn new(nats_url: &str, consumer_config: Option<ConsumerConfig>, batch_config: BatchConfig,
+                     redis_click_repository: Arc<RedisClickRepository>) -> Result<Self, PollingConsumerError> {
         let client = async_nats::connect(nats_url).await?;
-        let jetstream = async_nats::jetstream::new(client);
+        let jetstream = Arc::new(async_nats::jetstream::new(client));
 
         Ok(Self {
-            jetstream: Arc::new(jetstream),
+            dead_letters: DeadLetterQueue::new(jetstream.clone(), PERSISTER_CONSUMER_NAME),
+            jetstream,
             consumer_config: consumer_config.unwrap_or_default(),
-            click_repository: Arc::new(redis_click_repository)
+            batch_config,
+            click_repository: redis_click_repository,
+            running: AtomicBool::new(false),
         })
     }
 
+    pub fn jetstream(&self) -> Arc<jetstream::Context> {
+        self.jetstream.clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
     pub async fn create_consumer(
         &self,
     ) -> Result<jetstream::consumer::pull::Stream, PollingConsumerError> {
         let stream = get_stream(self.jetstream.clone()).await?;
 
         let config = jetstream::consumer::pull::Config {
-            durable_name: Some(CONSUMER_NAME.to_string()),
+            durable_name: Some(PERSISTER_CONSUMER_NAME.to_string()),
             deliver_policy: jetstream::consumer::DeliverPolicy::All,
             ack_policy: jetstream::consumer::AckPolicy::Explicit,
             ack_wait: self.consumer_config.ack_wait,
             max_deliver: self.consumer_config.max_deliver,
-            name: Some(CONSUMER_NAME.to_string()),
+            name: Some(PERSISTER_CONSUMER_NAME.to_string()),
             ..Default::default()
         };
 
@@ -52,6 +108,8 @@ impl ClickConsumer {
             .map_err(|e| PollingConsumerError::Processing(e.to_string()))?;
 
         let messages = consumer
+            .stream()
+            .max_messages_per_batch(self.batch_config.size)
             .messages()
             .await
             .map_err(|e| PollingConsumerError::Processing(e.to_string()))?;
@@ -59,21 +117,27 @@ impl ClickConsumer {
         Ok(messages)
     }
 
-    pub async fn run(&self) -> Result<(), PollingConsumerError> {
+    /// Persists clicks until the shutdown is triggered, then completes the saves and acks in flight.
+    /// Messages are gathered until a batch is full or its flush interval elapsed.
+    pub async fn run(&self, shutdown: Shutdown) -> Result<(), PollingConsumerError> {
+        get_or_create_dlq_stream(&self.jetstream)
+            .await
+            .map_err(|e| PollingConsumerError::Processing(e.to_string()))?;
         let consumer = self.create_consumer().await?;
         info!("Starting stream processor");
+        self.running.store(true, Ordering::Relaxed);
 
-        consumer
-            .map(|message_result| {
+        let batches = tokio_stream::StreamExt::chunks_timeout(
+            consumer.take_until(shutdown.wait()),
+            self.batch_config.size,
+            self.batch_config.flush_interval,
+        );
+
+        batches
+            .map(|batch| {
                 async move {
-                    match message_result {
-                        Ok(msg) => {
-                            info!("Processing message on subject: {}", msg.subject);
-                            if let Err(e) = self.handle_message(msg).await {
-                                error!("Error processing message: {}", e);
-                            }
-                        }
-                        Err(e) => error!("Error receiving message: {}", e),
+                    if let Err(e) = self.handle_batch(batch).await {
+                        error!("Error processing batch, its clicks will be redelivered: {}", e);
                     }
                 }
             })
@@ -81,26 +145,155 @@ impl ClickConsumer {
             .for_each(|_| future::ready(()))
             .await;
 
+        self.running.store(false, Ordering::Relaxed);
         Ok(())
     }
 
-    async fn handle_message(&self, message: jetstream::Message) -> Result<(), PollingConsumerError> {
-        let subject = message.subject.as_str();
+    /// Writes the newest click of each tile in one call, then acknowledges every message of the batch.
+    /// Nothing is acknowledged when the write fails, so the whole batch is delivered again,
+    /// except the messages on their last delivery which go to the dead letter stream.
+    async fn handle_batch<E: Display>(&self, batch: Vec<Result<jetstream::Message, E>>) -> Result<(), PollingConsumerError> {
+        let start = Instant::now();
+        let mut messages = Vec::with_capacity(batch.len());
+        let mut clicks = Vec::with_capacity(batch.len());
+        let mut dead_lettered = Vec::new();
 
-        let tile_id: u32 = subject
-            .strip_prefix(nats_commons::CLICK_SUBJECT_PREFIX)
-            .and_then(|id| id.parse().ok())
-            .ok_or_else(|| PollingConsumerError::Processing("Invalid subject format".to_string()))?;
-        let click: Click = clickplanet_proto::clicks::Click::decode(message.payload.clone())?;
+        for message_result in batch {
+            let message = match message_result {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("Error receiving message: {}", e);
+                    continue;
+                }
+            };
 
-        self.click_repository.save_click(tile_id, &click).await?;
+            if message.info().map(|info| info.delivered > 1).unwrap_or(false) {
+                REDELIVERIES.inc();
+            }
 
-        message
-            .ack()
-            .await
-            .map_err(|e| PollingConsumerError::Processing(e.to_string()))?;
+            match decode_click(&message) {
+                Ok(click) => {
+                    clicks.push(click);
+                    messages.push(message);
+                }
+                // Delivering it again would not help
+                Err(e) => {
+                    error!("Error processing message on subject {}: {}", message.subject, e);
+                    if self.dead_letter(&message, &e.to_string()).await {
+                        dead_lettered.push(message);
+                    }
+                }
+            }
+        }
+
+        acknowledge(&dead_lettered).await;
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        BATCH_SIZE.observe(messages.len() as f64);
+        let clicks = coalesce(clicks);
+        COALESCED.inc_by((messages.len() - clicks.len()) as u64);
+
+        let applied = match self.click_repository.save_clicks(&clicks).await {
+            Ok(applied) => applied,
+            Err(e) => {
+                let mut exhausted = Vec::new();
+                for message in messages {
+                    if is_last_delivery(&message, self.consumer_config.max_deliver)
+                        && self.dead_letter(&message, &e.to_string()).await {
+                        exhausted.push(message);
+                    }
+                }
+                acknowledge(&exhausted).await;
+                return Err(e.into());
+            }
+        };
+        debug!("Persisted a batch of {} clicks on {} tiles, {} applied", messages.len(), clicks.len(), applied);
+
+        // A failed ack only gets that click delivered again, and written again harmlessly
+        acknowledge(&messages).await;
+
+        PERSIST_SECONDS.observe(start.elapsed().as_secs_f64());
 
         Ok(())
     }
+
+    /// Whether the message is in the dead letter stream and can be acknowledged
+    async fn dead_letter(&self, message: &jetstream::Message, reason: &str) -> bool {
+        match self.dead_letters.send(message, reason).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to dead-letter message on {}: {}", message.subject, e);
+                false
+            }
+        }
+    }
+}
+
+async fn acknowledge(messages: &[jetstream::Message]) {
+    let acks = future::join_all(messages.iter().map(|message| message.ack())).await;
+    for error in acks.into_iter().filter_map(Result::err) {
+        error!("Failed to acknowledge click: {}", error);
+    }
+}
+
+fn decode_click(message: &jetstream::Message) -> Result<(u32, Click), PollingConsumerError> {
+    let tile_id: u32 = message.subject
+        .strip_prefix(CLICK_SUBJECT_PREFIX)
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| PollingConsumerError::Processing("Invalid subject format".to_string()))?;
+    let click: Click = clickplanet_proto::clicks::Click::decode(message.payload.clone())?;
+
+    Ok((tile_id, click))
+}
+
+/// Keeps the newest click of each tile, in the order of `supersedes`: the others would not be applied anyway
+fn coalesce(clicks: Vec<(u32, Click)>) -> Vec<(u32, Click)> {
+    let mut newest: HashMap<u32, Click> = HashMap::with_capacity(clicks.len());
+
+    for (tile_id, click) in clicks {
+        match newest.get(&tile_id) {
+            Some(kept) if !supersedes(&click, kept) => {}
+            _ => {
+                newest.insert(tile_id, click);
+            }
+        }
+    }
+
+    newest.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn click(tile_id: u32, country_id: &str, timestamp_ns: u64, node_id: u32) -> (u32, Click) {
+        (tile_id, Click {
+            tile_id: tile_id as i32,
+            country_id: country_id.to_string(),
+            timestamp_ns,
+            node_id,
+            click_id: format!("{}-{}", tile_id, timestamp_ns),
+        })
+    }
+
+    #[test]
+    fn test_batch_keeps_the_newest_click_per_tile() {
+        let mut coalesced = coalesce(vec![
+            click(1, "fr", 10, 0),
+            click(2, "ru", 5, 0),
+            click(1, "de", 30, 0),
+            click(1, "it", 20, 0),
+            click(2, "es", 5, 3),
+            click(2, "us", 5, 1),
+        ]);
+        coalesced.sort_by_key(|(tile_id, _)| *tile_id);
+
+        let owners: Vec<(u32, &str)> = coalesced.iter()
+            .map(|(tile_id, click)| (*tile_id, click.country_id.as_str()))
+            .collect();
+        assert_eq!(owners, vec![(1, "de"), (2, "es")]);
+    }
 }
 
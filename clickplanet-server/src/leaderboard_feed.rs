@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use clickplanet_proto::clicks::{LeaderboardDelta, LeaderboardEntry, LeaderboardResponse};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tracing::error;
+
+use crate::click_persistence::LeaderboardRepository;
+use crate::leaderboard_response;
+use crate::shutdown::Shutdown;
+
+/// Scores computed on one tick, shared by every listener.
+#[derive(Debug)]
+pub struct LeaderboardTick {
+    /// Incremented on every change, a listener which skipped one needs the snapshot
+    pub version: u64,
+    pub snapshot: LeaderboardResponse,
+    /// Changes since the previous version
+    pub delta: LeaderboardDelta,
+}
+
+/// Latest leaderboard, recomputed at most once per interval and only published when scores change.
+#[derive(Clone)]
+pub struct LeaderboardFeed {
+    receiver: watch::Receiver<Option<Arc<LeaderboardTick>>>,
+}
+
+impl LeaderboardFeed {
+    pub fn spawn(repository: Arc<dyn LeaderboardRepository>, interval: Duration, shutdown: Shutdown) -> Self {
+        let (sender, receiver) = watch::channel(None);
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut scores = HashMap::new();
+            let mut version = 0;
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = shutdown.wait() => return,
+                }
+
+                let current = match repository.leaderboard().await {
+                    Ok(current) => current,
+                    Err(e) => {
+                        error!("Error while computing the leaderboard feed: {:?}", e);
+                        continue;
+                    }
+                };
+
+                let changes = delta(&scores, &current);
+                if version > 0 && changes.entries.is_empty() {
+                    continue;
+                }
+
+                version += 1;
+                scores = current;
+                sender.send_replace(Some(Arc::new(LeaderboardTick {
+                    version,
+                    snapshot: leaderboard_response(scores.clone()),
+                    delta: changes,
+                })));
+            }
+        });
+
+        Self { receiver }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<Arc<LeaderboardTick>>> {
+        self.receiver.clone()
+    }
+}
+
+fn delta(previous: &HashMap<String, u32>, current: &HashMap<String, u32>) -> LeaderboardDelta {
+    let changed = current.iter()
+        .filter(|(country_id, score)| previous.get(*country_id) != Some(score))
+        .map(|(country_id, score)| LeaderboardEntry { country_id: country_id.clone(), score: *score });
+
+    let removed = previous.keys()
+        .filter(|country_id| !current.contains_key(*country_id))
+        .map(|country_id| LeaderboardEntry { country_id: country_id.clone(), score: 0 });
+
+    let mut entries: Vec<LeaderboardEntry> = changed.chain(removed).collect();
+    entries.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.country_id.cmp(&b.country_id)));
+
+    LeaderboardDelta { entries }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scores(entries: &[(&str, u32)]) -> HashMap<String, u32> {
+        entries.iter().map(|(country_id, score)| (country_id.to_string(), *score)).collect()
+    }
+
+    fn entry(country_id: &str, score: u32) -> LeaderboardEntry {
+        LeaderboardEntry { country_id: country_id.to_string(), score }
+    }
+
+    #[test]
+    fn test_delta_lists_changed_and_removed_countries() {
+        let previous = scores(&[("fr", 10), ("ru", 5), ("es", 1)]);
+        let current = scores(&[("fr", 10), ("ru", 6), ("de", 2)]);
+
+        assert_eq!(delta(&previous, &current).entries, vec![entry("ru", 6), entry("de", 2), entry("es", 0)]);
+        assert!(delta(&current, &current).entries.is_empty());
+    }
+}
@@ -0,0 +1,4 @@
+// Shared by the binaries without depending on their other modules,
+// so that each binary only compiles what it uses
+pub mod dead_letter;
+pub mod streams;
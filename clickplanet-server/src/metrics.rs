@@ -0,0 +1,21 @@
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use prometheus::{Encoder, TextEncoder};
+use tracing::error;
+
+/// Every metric of the process, in the Prometheus text format.
+/// Metrics are declared next to the code they measure and registered in the default registry.
+fn encode_metrics() -> String {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("Could not encode metrics: {:?}", e);
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+pub fn metrics_response() -> impl IntoResponse {
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], encode_metrics())
+}
@@ -2,27 +2,29 @@ use std::sync::Arc;
 use std::time::Duration;
 use async_nats::{jetstream, ConnectError};
 use async_nats::jetstream::Context;
+use async_nats::jetstream::consumer::DeliverPolicy;
 use thiserror::Error;
+use tracing::warn;
+use clickplanet_server::streams::{CLICK_STREAM_NAME, PERSISTER_CONSUMER_NAME};
 use crate::click_persistence::{ClickRepositoryError, LeaderboardError};
 
-pub const CLICK_SUBJECT_PREFIX: &'static str = "clicks.tile.";
-pub const CLICK_STREAM_NAME: &'static str = "CLICKS";
-
 #[derive(Clone, Debug)]
 pub struct ConsumerConfig {
     pub consumer_name: String,
     pub ack_wait: Duration,
     pub max_deliver: i64,
     pub concurrent_processors: usize,
+    pub deliver_policy: DeliverPolicy,
 }
 
 impl Default for ConsumerConfig {
     fn default() -> Self {
         Self {
-            consumer_name: "tile-state-processor".to_string(),
+            consumer_name: PERSISTER_CONSUMER_NAME.to_string(),
             ack_wait: Duration::from_secs(30),
             max_deliver: 3,
             concurrent_processors: 4,
+            deliver_policy: DeliverPolicy::All,
         }
     }
 }
@@ -46,3 +48,17 @@ pub async fn get_stream(jetstream: Arc<Context>) -> Result<jetstream::stream::St
         .await
         .map_err(|e| PollingConsumerError::Processing(e.to_string()))
 }
+
+/// Ack floor of the state persister consumer: every click up to this stream sequence is in Redis.
+/// Returns None when the persister has never run against this stream.
+pub async fn persisted_sequence(jetstream: Arc<Context>) -> Result<Option<u64>, PollingConsumerError> {
+    let stream = get_stream(jetstream).await?;
+
+    match stream.consumer_info(PERSISTER_CONSUMER_NAME).await {
+        Ok(info) => Ok(Some(info.ack_floor.stream_sequence)),
+        Err(e) => {
+            warn!("Could not read persister consumer info, replaying the whole stream: {}", e);
+            Ok(None)
+        }
+    }
+}
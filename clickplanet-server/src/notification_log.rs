@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, LazyLock, Mutex};
+use clickplanet_proto::clicks::UpdateNotification;
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::SendError;
+use uuid::Uuid;
+
+/// Messages a broadcast receiver skipped because it fell behind, by channel (`clicks`, `updates`) and receiver
+pub static BROADCAST_DROPPED: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "clickplanet_broadcast_dropped_total",
+    "Messages skipped by broadcast receivers lagging behind",
+    &["channel", "receiver"]
+).unwrap());
+
+/// Messages the slowest receiver of each broadcast channel has yet to read
+pub static BROADCAST_QUEUED: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "clickplanet_broadcast_queued",
+    "Messages not yet read by the slowest receiver of a broadcast channel",
+    &["channel"]
+).unwrap());
+
+/// Numbers ownership changes and keeps the most recent ones so reconnecting listeners can catch up.
+///
+/// Sequences are only meaningful within one server process, which is identified by a random epoch:
+/// a listener coming back with another epoch has to resync from a snapshot.
+pub struct NotificationLog {
+    epoch: u32,
+    capacity: usize,
+    sender: Arc<broadcast::Sender<UpdateNotification>>,
+    state: Mutex<LogState>,
+}
+
+struct LogState {
+    next_sequence: u64,
+    buffer: VecDeque<UpdateNotification>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Replay {
+    /// Every notification after the requested sequence, possibly none
+    Updates(Vec<UpdateNotification>),
+    /// The requested position is unknown or already evicted from the buffer
+    Resync,
+}
+
+impl NotificationLog {
+    pub fn new(sender: Arc<broadcast::Sender<UpdateNotification>>, capacity: usize) -> Self {
+        Self {
+            epoch: Uuid::new_v4().as_u128() as u32,
+            capacity,
+            sender,
+            state: Mutex::new(LogState {
+                next_sequence: 1,
+                buffer: VecDeque::with_capacity(capacity),
+            }),
+        }
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UpdateNotification> {
+        self.sender.subscribe()
+    }
+
+    pub fn publish(&self, mut notification: UpdateNotification) -> Result<usize, SendError<UpdateNotification>> {
+        let mut state = self.state.lock().unwrap();
+
+        notification.epoch = self.epoch;
+        notification.sequence = state.next_sequence;
+        state.next_sequence += 1;
+
+        if self.capacity > 0 {
+            if state.buffer.len() == self.capacity {
+                state.buffer.pop_front();
+            }
+            state.buffer.push_back(notification.clone());
+        }
+
+        // Broadcast while holding the lock so subscribers see sequences in order
+        self.sender.send(notification)
+    }
+
+    pub fn replay_after(&self, epoch: u32, last_sequence: u64) -> Replay {
+        if epoch != self.epoch {
+            return Replay::Resync;
+        }
+
+        let state = self.state.lock().unwrap();
+        let latest_sequence = state.next_sequence - 1;
+        let oldest_sequence = state.buffer.front()
+            .map(|notification| notification.sequence)
+            .unwrap_or(state.next_sequence);
+
+        if last_sequence > latest_sequence || last_sequence + 1 < oldest_sequence {
+            return Replay::Resync;
+        }
+
+        let skip = (last_sequence + 1 - oldest_sequence) as usize;
+        Replay::Updates(state.buffer.iter().skip(skip).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(tile_id: i32) -> UpdateNotification {
+        UpdateNotification {
+            tile_id,
+            country_id: "fr".to_string(),
+            previous_country_id: "ru".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn sequences(replay: Replay) -> Vec<u64> {
+        match replay {
+            Replay::Updates(updates) => updates.iter().map(|n| n.sequence).collect(),
+            Replay::Resync => panic!("Unexpected resync"),
+        }
+    }
+
+    #[test]
+    fn test_replay_after_sequence() {
+        let (sender, _receiver) = broadcast::channel(16);
+        let log = NotificationLog::new(Arc::new(sender), 3);
+
+        for tile_id in 0..5 {
+            log.publish(notification(tile_id)).unwrap();
+        }
+
+        // Sequences 3, 4 and 5 are still buffered
+        assert_eq!(sequences(log.replay_after(log.epoch(), 2)), vec![3, 4, 5]);
+        assert_eq!(sequences(log.replay_after(log.epoch(), 4)), vec![5]);
+        assert_eq!(sequences(log.replay_after(log.epoch(), 5)), Vec::<u64>::new());
+
+        assert_eq!(log.replay_after(log.epoch(), 1), Replay::Resync);
+        assert_eq!(log.replay_after(log.epoch(), 6), Replay::Resync);
+        assert_eq!(log.replay_after(log.epoch().wrapping_add(1), 4), Replay::Resync);
+    }
+
+    #[test]
+    fn test_published_notifications_are_numbered() {
+        let (sender, mut receiver) = broadcast::channel(16);
+        let log = NotificationLog::new(Arc::new(sender), 3);
+
+        log.publish(notification(1)).unwrap();
+        log.publish(notification(2)).unwrap();
+
+        let first = receiver.try_recv().unwrap();
+        let second = receiver.try_recv().unwrap();
+        assert_eq!((first.epoch, first.sequence), (log.epoch(), 1));
+        assert_eq!((second.epoch, second.sequence), (log.epoch(), 2));
+    }
+}
@@ -1,23 +1,40 @@
 use async_nats::jetstream;
 use async_nats::jetstream::consumer::pull::Stream;
-use clickplanet_proto::clicks::{Click, Ownership, UpdateNotification};
+use clickplanet_proto::clicks::{Click, ClickOutcome, Ownership, UpdateNotification};
 use futures_util::stream::Map;
 use futures_util::{future, StreamExt, TryStreamExt};
+use prometheus::{register_int_counter, IntCounter};
 use prost::Message;
+use std::cmp;
 use std::error::Error;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 use tokio::sync::broadcast::Receiver;
 use tokio::task::JoinHandle;
 use tracing::{error, info, warn};
+use uuid::Uuid;
 
-use crate::click_persistence::{ClickRepository, LeaderboardMaintainer, LeaderboardRepository};
+use crate::click_outcomes::ClickOutcomeRegistry;
+use crate::click_persistence::{click_order, ClickRepository, LeaderboardMaintainer, LeaderboardRepository};
+use clickplanet_server::dead_letter::{is_last_delivery, DeadLetterQueue};
+use crate::hybrid_clock::HybridClock;
 use crate::nats_commons;
+use crate::notification_log::{NotificationLog, BROADCAST_DROPPED};
 use crate::nats_commons::{get_stream, ConsumerConfig, PollingConsumerError};
+use crate::shutdown::Shutdown;
 use crate::redis_click_persistence::{RedisClickRepository, RedisPersistenceError};
 
 const CONSUMER_NAME: &'static str = "tile-ownership-update";
+const EPHEMERAL_INACTIVE_THRESHOLD: Duration = Duration::from_secs(60);
+
+static OWNERSHIP_CHANGES: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "clickplanet_ownership_changes_total",
+    "Tiles captured by a country other than their previous owner"
+).unwrap());
 
 #[derive(Error, Debug)]
 pub enum ConsumerError {
@@ -40,9 +57,13 @@ pub struct OwnershipUpdateService {
     click_repository: Arc<dyn ClickRepository>,
     leaderboard_maintainer: Arc<dyn LeaderboardMaintainer>,
     click_sender: Arc<broadcast::Sender<Click>>,
-    update_tx: Arc<broadcast::Sender<UpdateNotification>>,
-    jetstream: Arc<jetstream::Context>,
+    notification_log: Arc<NotificationLog>,
+    outcomes: Arc<ClickOutcomeRegistry>,
+    clock: Arc<HybridClock>,
+    jetstream: Option<Arc<jetstream::Context>>,
+    dead_letters: Option<DeadLetterQueue>,
     consumer_config: ConsumerConfig,
+    running: Arc<AtomicBool>,
 }
 
 impl OwnershipUpdateService {
@@ -50,57 +71,119 @@ impl OwnershipUpdateService {
         click_repository: Arc<dyn ClickRepository>,
         leaderboard_maintainer: Arc<dyn LeaderboardMaintainer>,
         click_sender: Arc<broadcast::Sender<Click>>,
-        update_sender: Arc<broadcast::Sender<UpdateNotification>>,
-        jetstream: Arc<jetstream::Context>,
+        notification_log: Arc<NotificationLog>,
+        outcomes: Arc<ClickOutcomeRegistry>,
+        clock: Arc<HybridClock>,
         consumer_config: Option<ConsumerConfig>,
     ) -> Self {
         Self {
             click_repository,
             leaderboard_maintainer,
             click_sender,
-            update_tx: update_sender,
-            jetstream,
+            notification_log,
+            outcomes,
+            clock,
+            jetstream: None,
+            dead_letters: None,
             consumer_config: consumer_config.unwrap_or_default(),
+            running: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub async fn run(&self) -> Result<(), ConsumerError> {
+    /// Also consumes the clicks of the stream, those of the other replicas included
+    pub fn with_jetstream(mut self, jetstream: Arc<jetstream::Context>) -> Self {
+        self.dead_letters = Some(DeadLetterQueue::new(jetstream.clone(), CONSUMER_NAME));
+        self.jetstream = Some(jetstream);
+        self
+    }
+
+    /// Whether clicks are being consumed, from JetStream when it is configured
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Consumes clicks until the shutdown is triggered, then applies the clicks still waiting in the
+    /// in-process channel and completes those in flight. Trigger it once no more click can be accepted.
+    pub async fn run(&self, shutdown: Shutdown) -> Result<(), ConsumerError> {
         let click_rx = self.click_sender.subscribe();
-        let nats_consumer: Stream = self.create_consumer().await?;
         let self_arc = Arc::new(self.clone());
 
-        let nats_handle: JoinHandle<()> = self.clone().launch_nats_consumer(nats_consumer).await;
-        let click_handle: JoinHandle<()> = self_arc.launch_direct_consumer(click_rx);
+        let Some(jetstream) = self.jetstream.clone() else {
+            info!("No JetStream configured, consuming in-process clicks only");
+            self.running.store(true, Ordering::Relaxed);
+            let result = self_arc.launch_direct_consumer(click_rx, shutdown.clone()).await;
+            self.running.store(false, Ordering::Relaxed);
 
+            if let Err(e) = result {
+                error!("Click processing task failed: {:?}", e);
+            } else if !shutdown.is_triggered() {
+                error!("Unexpected click handle exit");
+            }
+            return Ok(());
+        };
+
+        let nats_consumer: Stream = self.create_consumer(jetstream).await?;
+
+        let mut nats_handle: JoinHandle<()> = self.clone().launch_nats_consumer(nats_consumer, shutdown.clone()).await;
+        let mut click_handle: JoinHandle<()> = self_arc.launch_direct_consumer(click_rx, shutdown.clone());
+        self.running.store(true, Ordering::Relaxed);
+
+        // On shutdown both consumers stop pulling, the one still completing its clicks is awaited
         tokio::select! {
-            result = nats_handle => {
+            result = &mut nats_handle => {
                 if let Err(e) = result {
                     error!("NATS processing task failed: {:?}", e);
+                } else if shutdown.is_triggered() {
+                    let _ = click_handle.await;
                 } else {
                     error!("Unexpected NATS exit");
                 }
             }
-            result = click_handle => {
+            result = &mut click_handle => {
                 if let Err(e) = result {
                     error!("Click processing task failed: {:?}", e);
+                } else if shutdown.is_triggered() {
+                    let _ = nats_handle.await;
                 } else {
                     error!("Unexpected click handle exit");
                 }
             }
         }
+        self.running.store(false, Ordering::Relaxed);
 
         Ok(())
     }
 
-    fn launch_direct_consumer(self: Arc<Self>, click_rx: broadcast::Receiver<Click>) -> JoinHandle<()> {
+    fn launch_direct_consumer(self: Arc<Self>, click_rx: broadcast::Receiver<Click>, shutdown: Shutdown) -> JoinHandle<()> {
         tokio::spawn({
             let this = self;
             let config = this.consumer_config.concurrent_processors;  // Get the config value before the move
             async move {
-                futures::stream::unfold(click_rx, |mut rx| async {
-                    match rx.recv().await {
-                        Ok(click) => Some((click, rx)),
-                        Err(_) => None,
+                futures::stream::unfold(click_rx, move |mut rx| {
+                    let shutdown = shutdown.clone();
+                    async move {
+                        loop {
+                            let received = tokio::select! {
+                                biased;
+                                received = rx.recv() => received,
+                                // The clicks left in the channel were already accepted, they are applied before stopping
+                                _ = shutdown.wait() => match rx.try_recv() {
+                                    Ok(click) => Ok(click),
+                                    Err(TryRecvError::Lagged(missed_clicks)) => Err(RecvError::Lagged(missed_clicks)),
+                                    Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => return None,
+                                },
+                            };
+
+                            match received {
+                                Ok(click) => return Some((click, rx)),
+                                // Skipped clicks are still applied through JetStream when it is configured
+                                Err(RecvError::Lagged(missed_clicks)) => {
+                                    warn!("In-process click consumer lagged behind by {} clicks", missed_clicks);
+                                    BROADCAST_DROPPED.with_label_values(&["clicks", "ownership"]).inc_by(missed_clicks);
+                                }
+                                Err(RecvError::Closed) => return None,
+                            }
+                        }
                     }
                 })
                     .map(move |click| {
@@ -118,7 +201,7 @@ impl OwnershipUpdateService {
         })
     }
 
-    async fn launch_nats_consumer(self, stream: Stream) -> JoinHandle<()> {
+    async fn launch_nats_consumer(self, stream: Stream, shutdown: Shutdown) -> JoinHandle<()> {
         let self_arc = Arc::new(self);
 
         tokio::spawn({
@@ -130,7 +213,8 @@ impl OwnershipUpdateService {
                 if let Err(e) = process_nats_messages(
                     stream,
                     self_arc,
-                    config
+                    config,
+                    shutdown,
                 ).await {
                     error!("NATS message processing failed: {:?}", e);
                 }
@@ -143,10 +227,8 @@ impl OwnershipUpdateService {
             Ok(click) => click,
             Err(e) => {
                 error!("Failed to decode message payload: {}", e);
-                // Try to ack malformed messages to avoid redelivery
-                if let Err(ack_err) = message.ack().await {
-                    error!("Failed to ack malformed message: {}", ack_err);
-                }
+                // Delivering it again would not help
+                self.dead_letter(message, &format!("decode error: {}", e)).await;
                 return Ok(());
             }
         };
@@ -160,23 +242,46 @@ impl OwnershipUpdateService {
             }
             Err(e) => {
                 error!("Failed to process click: {}", e);
-                if let Err(ack_err) = message.ack().await {
-                    error!("Also failed to acknowledge failed message: {}", ack_err);
+                // Left unacknowledged it is delivered again, until the last attempt
+                if is_last_delivery(&message, self.consumer_config.max_deliver) {
+                    self.dead_letter(message, &format!("processing error: {}", e)).await;
                 }
                 Ok(())
             }
         }
     }
 
-    async fn create_consumer(&self) -> Result<jetstream::consumer::pull::Stream, PollingConsumerError> {
-        let stream = get_stream(self.jetstream.clone()).await?;
+    /// Acknowledges the message once it is in the dead letter stream, otherwise it is delivered again
+    async fn dead_letter(&self, message: jetstream::Message, reason: &str) {
+        let Some(dead_letters) = &self.dead_letters else {
+            return;
+        };
+
+        if let Err(e) = dead_letters.send(&message, reason).await {
+            error!("Failed to dead-letter message on {}: {}", message.subject, e);
+            return;
+        }
+        if let Err(e) = message.ack().await {
+            error!("Failed to ack dead-lettered message: {}", e);
+        }
+    }
+
+    async fn create_consumer(&self, jetstream: Arc<jetstream::Context>) -> Result<jetstream::consumer::pull::Stream, PollingConsumerError> {
+        let stream = get_stream(jetstream).await?;
+
+        // Every replica needs the full click stream for its own in-memory map and websocket
+        // broadcast, so each one gets its own ephemeral consumer instead of sharing a durable one.
+        // The server drops it once this replica stops pulling.
+        let consumer_name = format!("{}-{}", CONSUMER_NAME, Uuid::new_v4());
+        info!("Creating consumer {} from {:?}", consumer_name, self.consumer_config.deliver_policy);
 
         let config = jetstream::consumer::pull::Config {
-            durable_name: Some(CONSUMER_NAME.to_string()),
-            deliver_policy: jetstream::consumer::DeliverPolicy::All,
+            name: Some(consumer_name),
+            deliver_policy: self.consumer_config.deliver_policy,
             ack_policy: jetstream::consumer::AckPolicy::Explicit,
             ack_wait: self.consumer_config.ack_wait,
             max_deliver: self.consumer_config.max_deliver,
+            inactive_threshold: EPHEMERAL_INACTIVE_THRESHOLD,
             ..Default::default()
         };
 
@@ -192,7 +297,40 @@ impl OwnershipUpdateService {
     }
 
     async fn process_click(&self, click: Click) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let previous_ownership: Option<Ownership> = self.click_repository.save_click(click.tile_id as u32, &click).await?;
+        // Clicks are validated before being published, this only guards against older stream content
+        let Ok(tile_id) = u32::try_from(click.tile_id) else {
+            warn!("Ignoring click on invalid tile {}", click.tile_id);
+            return Ok(());
+        };
+
+        // Clicks taken here from now on are ordered after those of the other servers seen so far
+        self.clock.observe(click.timestamp_ns);
+
+        let previous_ownership: Option<Ownership> = self.click_repository.save_click(tile_id, &click).await?;
+
+        // An equal timestamp and node is this very click coming back through the other path: nothing to report
+        let outcome = match &previous_ownership {
+            None => Some(ClickOutcome::Captured),
+            Some(previous) => match click_order(&click, previous) {
+                cmp::Ordering::Less => Some(ClickOutcome::Superseded),
+                cmp::Ordering::Equal => None,
+                cmp::Ordering::Greater if previous.country_id == click.country_id => Some(ClickOutcome::AlreadyOwned),
+                cmp::Ordering::Greater => Some(ClickOutcome::Captured),
+            },
+        };
+
+        if let Some(outcome) = outcome {
+            self.outcomes.resolve(&click.click_id, outcome);
+        }
+
+        // Outdated clicks were not written, they must not show up as ownership changes
+        if outcome != Some(ClickOutcome::Captured) {
+            return Ok(());
+        }
+
+        if previous_ownership.as_ref().is_none_or(|previous| previous.country_id != click.country_id) {
+            OWNERSHIP_CHANGES.inc();
+        }
 
         // Only process ownership change if:
         // 1. There was a previous owner (Some) AND
@@ -203,14 +341,15 @@ impl OwnershipUpdateService {
                     tile_id: click.tile_id.try_into().unwrap(),
                     previous_country_id: last_ownership.country_id,
                     country_id: click.country_id,
+                    ..Default::default()
                 };
 
-                self.leaderboard_maintainer.update_country_index(click.tile_id as u32,
+                self.leaderboard_maintainer.update_country_index(tile_id,
                                                                  notification.country_id.as_str(),
                                                                  Some(notification.previous_country_id.as_str())
                                                                      .filter(|string| !string.is_empty())).await;
 
-                let result = self.update_tx.send(notification);
+                let result = self.notification_log.publish(notification);
                 if let Err(e) = result {
                     tracing::debug!("No listener for ownership update: {:?}", e);
                 }
@@ -225,8 +364,10 @@ async fn process_nats_messages(
     nats_stream: Stream,
     owner: Arc<OwnershipUpdateService>,
     config: usize,
+    shutdown: Shutdown,
 ) -> Result<(), ConsumerError> {
     nats_stream
+        .take_until(async move { shutdown.wait().await })
         .map(|message_result| {
             message_result.map_err(|e| ConsumerError::ProcessingError(e.to_string()))
         })
@@ -243,4 +384,51 @@ async fn process_nats_messages(
         .await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_memory_click_persistence::PapayaClickRepository;
+
+    #[tokio::test]
+    async fn test_accepted_clicks_are_applied_on_shutdown() {
+        let repository = Arc::new(PapayaClickRepository::new());
+        let (click_sender, _) = broadcast::channel(1000);
+        let click_sender = Arc::new(click_sender);
+        let (update_sender, _) = broadcast::channel(1000);
+
+        let service = OwnershipUpdateService::new(
+            repository.clone(),
+            repository.clone(),
+            click_sender.clone(),
+            Arc::new(NotificationLog::new(Arc::new(update_sender), 100)),
+            Arc::new(ClickOutcomeRegistry::new()),
+            Arc::new(HybridClock::new(1)),
+            None,
+        );
+
+        let shutdown = Shutdown::new();
+        let run = tokio::spawn({
+            let service = service.clone();
+            let shutdown = shutdown.clone();
+            async move { service.run(shutdown).await }
+        });
+        while !service.is_running() {
+            tokio::task::yield_now().await;
+        }
+
+        for tile_id in 1..=500 {
+            click_sender.send(Click {
+                tile_id,
+                country_id: "fr".to_string(),
+                timestamp_ns: 1000,
+                node_id: 1,
+                click_id: format!("click-{}", tile_id),
+            }).unwrap();
+        }
+        shutdown.trigger();
+
+        run.await.unwrap().unwrap();
+        assert_eq!(repository.tile_count(), 500);
+    }
+}
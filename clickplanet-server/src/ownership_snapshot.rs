@@ -0,0 +1,338 @@
+use std::io::Write;
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+use axum::http::header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use clickplanet_proto::clicks::{CompactOwnershipState, OwnershipState};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prometheus::{register_histogram, Histogram};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tracing::error;
+
+use crate::click_persistence::ClickRepository;
+use crate::in_memory_click_persistence::PapayaClickRepository;
+use crate::rpc_codec::RpcFormat;
+use crate::shutdown::Shutdown;
+
+/// Fast levels: the snapshot is rebuilt often and every listener saves the same bytes
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+static SNAPSHOT_BUILD_SECONDS: LazyLock<Histogram> = LazyLock::new(|| register_histogram!(
+    "clickplanet_ownership_snapshot_build_duration_seconds",
+    "Time to encode the ownership snapshot, compressions excluded"
+).unwrap());
+
+/// Content codings the snapshot is compressed with, by order of preference
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Brotli = 0,
+    Zstd = 1,
+    Gzip = 2,
+}
+
+impl ContentEncoding {
+    const PREFERENCE: [ContentEncoding; 3] = [ContentEncoding::Brotli, ContentEncoding::Zstd, ContentEncoding::Gzip];
+
+    fn token(self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Brotli => {
+                let mut output = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut output, 64 * 1024, BROTLI_QUALITY, BROTLI_WINDOW);
+                    writer.write_all(bytes)?;
+                }
+                Ok(output)
+            }
+            ContentEncoding::Zstd => zstd::encode_all(bytes, ZSTD_LEVEL),
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Preferred coding among those the client accepts, none when it only accepts the identity
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let accepted: Vec<(&str, f32)> = headers.get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|coding| {
+                let mut parameters = coding.split(';');
+                let name = parameters.next().unwrap_or("").trim();
+                let quality = parameters
+                    .find_map(|parameter| parameter.trim().strip_prefix("q="))
+                    .and_then(|quality| quality.trim().parse().ok())
+                    .unwrap_or(1.0);
+                (name, quality)
+            })
+            .collect();
+
+        let quality = |encoding: ContentEncoding| accepted.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.token()))
+            .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0);
+
+        Self::PREFERENCE.into_iter()
+            .map(|encoding| (encoding, quality(encoding)))
+            .filter(|(_, quality)| *quality > 0.0)
+            .fold(None, |best: Option<(Self, f32)>, candidate| match best {
+                Some((_, best_quality)) if best_quality >= candidate.1 => best,
+                _ => Some(candidate),
+            })
+            .map(|(encoding, _)| encoding)
+    }
+}
+
+/// A response body, compressed in a coding the first time a client asks for it:
+/// most rebuilds are only ever served in one or two of them.
+struct EncodedBody {
+    identity: Bytes,
+    /// By `ContentEncoding` discriminant, None when the compression failed
+    compressed: [OnceLock<Option<Bytes>>; ContentEncoding::PREFERENCE.len()],
+}
+
+impl EncodedBody {
+    fn new(identity: Bytes) -> Self {
+        Self { identity, compressed: Default::default() }
+    }
+
+    /// Compresses on the calling thread when the coding is asked for the first time
+    fn get(&self, encoding: Option<ContentEncoding>) -> (Option<ContentEncoding>, Bytes) {
+        let compressed = encoding.and_then(|encoding| {
+            let bytes = self.compressed[encoding as usize].get_or_init(|| match encoding.compress(&self.identity) {
+                Ok(bytes) => Some(Bytes::from(bytes)),
+                Err(e) => {
+                    error!("Could not compress the ownership snapshot with {}: {:?}", encoding.token(), e);
+                    None
+                }
+            });
+            bytes.clone().map(|bytes| (Some(encoding), bytes))
+        });
+
+        compressed.unwrap_or_else(|| (None, self.identity.clone()))
+    }
+}
+
+/// One response message encoded once for all the clients asking for it, in every format and coding.
+pub struct EncodedMessage {
+    etag: HeaderValue,
+    protobuf: EncodedBody,
+    json: EncodedBody,
+}
+
+impl EncodedMessage {
+    pub fn build<M: Message>(message: &M) -> Self {
+        let protobuf = RpcFormat::Protobuf.encode(message);
+
+        // Derived from the content rather than the map version, which restarts at every boot and differs between replicas.
+        // Weak as both formats and every coding carry the same ownerships.
+        let digest: String = Sha256::digest(&protobuf)[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+        let etag = HeaderValue::from_str(&format!("W/\"{}\"", digest))
+            .expect("hexadecimal digits are a valid header value");
+
+        Self {
+            etag,
+            json: EncodedBody::new(RpcFormat::Json.encode(message)),
+            protobuf: EncodedBody::new(protobuf),
+        }
+    }
+
+    /// 304 when the client already has this snapshot, otherwise the body in its format and preferred coding.
+    /// Can block on a compression, see `EncodedBody::get`.
+    pub fn respond(&self, format: RpcFormat, headers: &HeaderMap) -> Response {
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(ETAG, self.etag.clone());
+        response_headers.insert(VARY, HeaderValue::from_static("accept, accept-encoding"));
+        // Browsers keep the snapshot but revalidate it on every load
+        response_headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+        if self.matches(headers) {
+            return (StatusCode::NOT_MODIFIED, response_headers).into_response();
+        }
+
+        let body = match format {
+            RpcFormat::Protobuf => &self.protobuf,
+            RpcFormat::Json => &self.json,
+        };
+        let (encoding, bytes) = body.get(ContentEncoding::negotiate(headers));
+
+        response_headers.insert(CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+        if let Some(encoding) = encoding {
+            response_headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.token()));
+        }
+
+        (response_headers, bytes).into_response()
+    }
+
+    /// If-None-Match uses the weak comparison
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        let etag = weak_opaque_tag(self.etag.to_str().unwrap_or(""));
+
+        headers.get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || weak_opaque_tag(candidate) == etag)
+    }
+}
+
+/// Every ownership of the map at one point in time, as an `OwnershipState` and as a `CompactOwnershipState`.
+pub struct OwnershipSnapshot {
+    pub full: EncodedMessage,
+    pub compact: EncodedMessage,
+    pub compact_with_timestamps: EncodedMessage,
+}
+
+impl OwnershipSnapshot {
+    pub fn build(state: &OwnershipState) -> Self {
+        Self {
+            full: EncodedMessage::build(state),
+            compact: EncodedMessage::build(&CompactOwnershipState::from_state(state, false)),
+            compact_with_timestamps: EncodedMessage::build(&CompactOwnershipState::from_state(state, true)),
+        }
+    }
+}
+
+fn weak_opaque_tag(etag: &str) -> &str {
+    etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+/// Latest ownership snapshot, rebuilt at most once per interval and only when the map changed.
+#[derive(Clone)]
+pub struct OwnershipSnapshots {
+    receiver: watch::Receiver<Option<Arc<OwnershipSnapshot>>>,
+}
+
+impl OwnershipSnapshots {
+    pub fn spawn(repository: Arc<PapayaClickRepository>, interval: Duration, shutdown: Shutdown) -> Self {
+        let (sender, receiver) = watch::channel(None);
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut built_version = None;
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = shutdown.wait() => return,
+                }
+
+                // Read before the map: changes made while building are picked up by the next tick
+                let version = repository.version();
+                if built_version == Some(version) {
+                    continue;
+                }
+
+                let state = match repository.get_ownerships().await {
+                    Ok(state) => state,
+                    Err(e) => {
+                        error!("Error while building the ownership snapshot: {:?}", e);
+                        continue;
+                    }
+                };
+
+                let start = Instant::now();
+                let snapshot = match tokio::task::spawn_blocking(move || OwnershipSnapshot::build(&state)).await {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        error!("Ownership snapshot build failed: {:?}", e);
+                        continue;
+                    }
+                };
+                SNAPSHOT_BUILD_SECONDS.observe(start.elapsed().as_secs_f64());
+
+                built_version = Some(version);
+                sender.send_replace(Some(Arc::new(snapshot)));
+            }
+        });
+
+        Self { receiver }
+    }
+
+    /// Waits for the first snapshot after startup
+    pub async fn latest(&self) -> Option<Arc<OwnershipSnapshot>> {
+        let mut receiver = self.receiver.clone();
+        let snapshot = receiver.wait_for(Option::is_some).await.ok()?;
+        snapshot.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use axum::body::to_bytes;
+    use clickplanet_proto::clicks::Ownership;
+    use flate2::read::GzDecoder;
+
+    fn state() -> OwnershipState {
+        OwnershipState {
+            ownerships: (1..1000).map(|tile_id| Ownership {
+                tile_id,
+                country_id: if tile_id % 3 == 0 { "fr".to_string() } else { "ru".to_string() },
+                timestamp_ns: 1000 + tile_id as u64,
+                node_id: 0,
+            }).collect(),
+        }
+    }
+
+    fn headers(entries: &[(axum::http::HeaderName, &str)]) -> HeaderMap {
+        entries.iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_encoding_follows_accept_encoding() {
+        assert_eq!(ContentEncoding::negotiate(&headers(&[])), None);
+        assert_eq!(ContentEncoding::negotiate(&headers(&[(ACCEPT_ENCODING, "gzip, deflate, br, zstd")])), Some(ContentEncoding::Brotli));
+        assert_eq!(ContentEncoding::negotiate(&headers(&[(ACCEPT_ENCODING, "gzip;q=1.0, br;q=0.5")])), Some(ContentEncoding::Gzip));
+        assert_eq!(ContentEncoding::negotiate(&headers(&[(ACCEPT_ENCODING, "*, br;q=0")])), Some(ContentEncoding::Zstd));
+        assert_eq!(ContentEncoding::negotiate(&headers(&[(ACCEPT_ENCODING, "identity")])), None);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_is_compressed_and_revalidated() {
+        let snapshot = OwnershipSnapshot::build(&state());
+
+        let response = snapshot.full.respond(RpcFormat::Protobuf, &headers(&[(ACCEPT_ENCODING, "gzip")]));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&body[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(OwnershipState::decode(&decompressed[..]).unwrap(), state());
+
+        let revalidated = snapshot.full.respond(RpcFormat::Json, &headers(&[(IF_NONE_MATCH, &etag)]));
+        assert_eq!(revalidated.status(), StatusCode::NOT_MODIFIED);
+        assert!(to_bytes(revalidated.into_body(), usize::MAX).await.unwrap().is_empty());
+
+        let mut changed = state();
+        changed.ownerships[0].country_id = "es".to_string();
+        let changed = OwnershipSnapshot::build(&changed);
+        assert_eq!(changed.full.respond(RpcFormat::Protobuf, &headers(&[(IF_NONE_MATCH, &etag)])).status(), StatusCode::OK);
+    }
+}
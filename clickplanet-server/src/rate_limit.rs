@@ -0,0 +1,538 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::header::{CONTENT_TYPE, RETRY_AFTER};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use deadpool_redis::{redis, Config as RedisConfig, CreatePoolError, PoolError, Runtime};
+use serde_json::json;
+use thiserror::Error;
+use tower::{Layer, Service};
+use tracing::{debug, warn};
+
+const FORWARDED_FOR: &str = "x-forwarded-for";
+const GRPC_CONTENT_TYPE: &str = "application/grpc";
+/// `RESOURCE_EXHAUSTED`, what gRPC clients expect from a rate limiter
+const GRPC_RESOURCE_EXHAUSTED: &str = "8";
+const REDIS_KEY_PREFIX: &str = "ratelimit:";
+
+static RATE_LIMITED_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "clickplanet_rate_limited_requests_total",
+    "Requests answered 429 by the rate limiter, by route",
+    &["route"]
+).unwrap());
+
+/// Buckets tracked in memory before the full ones, which carry no information, are dropped,
+/// then the least recently used ones until `TRACKED_BUCKETS_AFTER_EVICTION` are left.
+const MAX_TRACKED_BUCKETS: usize = 100_000;
+/// Leaves room for many new clients before the buckets are scanned again
+const TRACKED_BUCKETS_AFTER_EVICTION: usize = MAX_TRACKED_BUCKETS / 10 * 9;
+
+/// Token bucket refill, in the same way for the in-memory buckets and the Redis ones.
+///
+/// Buckets are hashes `{tokens, updated_at}` expiring once they would be full again.
+/// The Redis clock is used so replicas with drifting clocks share the same buckets.
+/// Returns the seconds to wait before a token is available, "0" when one was taken.
+static ACQUIRE_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| redis::Script::new(r#"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at')
+local tokens = tonumber(bucket[1]) or burst
+local updated_at = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated_at) * rate)
+
+local retry_after = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    retry_after = (1 - tokens) / rate
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', tostring(now))
+redis.call('PEXPIRE', KEYS[1], math.ceil((burst - tokens) / rate * 1000) + 1000)
+
+return tostring(retry_after)
+"#));
+
+/// Requests per second a client is granted on a route, with bursts of up to `burst` requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RateLimitParseError {
+    #[error("Expected <path>=<requests per second>[:<burst>], got {0:?}")]
+    Malformed(String),
+    #[error("Rate and burst must be positive in {0:?}")]
+    NotPositive(String),
+}
+
+/// `<path>=<requests per second>[:<burst>]`, the burst defaults to one second of requests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteRateLimit {
+    pub path: String,
+    pub limit: RateLimit,
+}
+
+impl FromStr for RouteRateLimit {
+    type Err = RateLimitParseError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let malformed = || RateLimitParseError::Malformed(spec.to_string());
+
+        let (path, limit) = spec.trim().split_once('=').ok_or_else(malformed)?;
+        let (per_second, burst) = match limit.split_once(':') {
+            Some((per_second, burst)) => (
+                per_second.parse::<f64>().map_err(|_| malformed())?,
+                Some(burst.parse::<u32>().map_err(|_| malformed())?),
+            ),
+            None => (limit.parse::<f64>().map_err(|_| malformed())?, None),
+        };
+        let burst = burst.unwrap_or(per_second.ceil() as u32);
+
+        if !path.starts_with('/') {
+            return Err(malformed());
+        }
+        if per_second.is_nan() || per_second <= 0.0 || burst == 0 {
+            return Err(RateLimitParseError::NotPositive(spec.to_string()));
+        }
+
+        Ok(RouteRateLimit {
+            path: path.to_string(),
+            limit: RateLimit { per_second, burst },
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+#[derive(Error, Debug)]
+pub enum RateLimitStoreError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("Redis pool error: {0}")]
+    RedisPool(#[from] PoolError),
+    #[error("Redis create pool error: {0}")]
+    CreateRedisPool(#[from] CreatePoolError),
+}
+
+/// Where the token buckets live. Replicas sharing a store share the limits of a client.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes one token from the bucket `key`, created full the first time it is used
+    async fn acquire(&self, key: &str, limit: RateLimit) -> Result<Decision, RateLimitStoreError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum RateLimitStoreKind {
+    /// Buckets local to each replica
+    Memory,
+    /// Buckets shared by all replicas through REDIS_URL
+    Redis,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    full_at: Instant,
+}
+
+impl Bucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Bucket { tokens: limit.burst as f64, updated_at: now, full_at: now }
+    }
+
+    fn acquire(&mut self, limit: RateLimit, now: Instant) -> Decision {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        let burst = limit.burst as f64;
+        let mut tokens = (self.tokens + elapsed * limit.per_second).min(burst);
+
+        let decision = if tokens >= 1.0 {
+            tokens -= 1.0;
+            Decision::Allowed
+        } else {
+            Decision::Limited { retry_after: Duration::from_secs_f64((1.0 - tokens) / limit.per_second) }
+        };
+
+        self.tokens = tokens;
+        self.updated_at = now;
+        self.full_at = now + Duration::from_secs_f64((burst - tokens) / limit.per_second);
+
+        decision
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl InMemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for InMemoryRateLimitStore {
+    async fn acquire(&self, key: &str, limit: RateLimit) -> Result<Decision, RateLimitStoreError> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_BUCKETS && !buckets.contains_key(key) {
+            evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key.to_string())
+            .or_insert_with(|| Bucket::full(limit, now));
+
+        Ok(bucket.acquire(limit, now))
+    }
+}
+
+/// Leaves at most `TRACKED_BUCKETS_AFTER_EVICTION` buckets, even when clients keep coming with new keys.
+/// A client whose bucket was evicted starts over with a full one.
+fn evict(buckets: &mut HashMap<String, Bucket>, now: Instant) {
+    buckets.retain(|_, bucket| bucket.full_at > now);
+
+    let Some(excess) = buckets.len().checked_sub(TRACKED_BUCKETS_AFTER_EVICTION).filter(|excess| *excess > 0) else {
+        return;
+    };
+    let mut updates: Vec<Instant> = buckets.values().map(|bucket| bucket.updated_at).collect();
+    let (_, &mut newest_evicted, _) = updates.select_nth_unstable(excess - 1);
+    buckets.retain(|_, bucket| bucket.updated_at > newest_evicted);
+}
+
+pub struct RedisRateLimitStore {
+    redis_pool: deadpool_redis::Pool,
+}
+
+impl RedisRateLimitStore {
+    pub fn new(redis_url: &str) -> Result<Self, RateLimitStoreError> {
+        let redis_pool = RedisConfig::from_url(redis_url).create_pool(Some(Runtime::Tokio1))?;
+
+        Ok(Self { redis_pool })
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for RedisRateLimitStore {
+    async fn acquire(&self, key: &str, limit: RateLimit) -> Result<Decision, RateLimitStoreError> {
+        let mut redis_conn = self.redis_pool.get().await?;
+
+        let retry_after: String = ACQUIRE_SCRIPT
+            .key(format!("{}{}", REDIS_KEY_PREFIX, key))
+            .arg(limit.per_second)
+            .arg(limit.burst)
+            .invoke_async(&mut redis_conn)
+            .await?;
+
+        Ok(match retry_after.parse::<f64>() {
+            Ok(seconds) if seconds > 0.0 => Decision::Limited { retry_after: Duration::from_secs_f64(seconds) },
+            _ => Decision::Allowed,
+        })
+    }
+}
+
+/// How a client is told apart from the others.
+#[derive(Clone, Debug, Default)]
+pub struct ClientIdentity {
+    /// Reverse proxies in front of the server: the address appended to `X-Forwarded-For` by the
+    /// outermost one is the client. With none, the header is ignored as anyone can forge it.
+    pub trusted_proxy_hops: usize,
+    /// Header carrying a session token, preferred over the address when present.
+    /// Only meaningful when tokens are issued or checked by a trusted front.
+    pub session_header: Option<HeaderName>,
+}
+
+impl ClientIdentity {
+    pub fn client_key(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<String> {
+        let session = self.session_header.as_ref()
+            .and_then(|header| headers.get(header))
+            .and_then(|value| value.to_str().ok())
+            .filter(|token| !token.is_empty());
+
+        if let Some(token) = session {
+            // Tokens are not kept as is in the store
+            let mut hasher = DefaultHasher::new();
+            token.hash(&mut hasher);
+            return Some(format!("session:{:016x}", hasher.finish()));
+        }
+
+        self.forwarded_for(headers)
+            .or(peer)
+            .map(|address| format!("ip:{}", address))
+    }
+
+    fn forwarded_for(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        if self.trusted_proxy_hops == 0 {
+            return None;
+        }
+
+        let addresses: Vec<&str> = headers.get_all(FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        // Fewer entries than proxies: the request did not come through all of them
+        let index = addresses.len().checked_sub(self.trusted_proxy_hops)?;
+        addresses[index].parse().ok()
+    }
+}
+
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    identity: ClientIdentity,
+    routes: HashMap<String, RateLimit>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>, identity: ClientIdentity, routes: Vec<RouteRateLimit>) -> Self {
+        let routes = routes.into_iter()
+            .map(|route| (route.path, route.limit))
+            .collect();
+
+        Self { store, identity, routes }
+    }
+
+    /// Bucket of the client on the route it calls, none when the route is not limited
+    fn bucket(&self, request: &Request) -> Option<(String, RateLimit)> {
+        let path = request.uri().path();
+        let limit = *self.routes.get(path)?;
+
+        let peer = request.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+        let client_key = self.identity.client_key(request.headers(), peer)?;
+
+        Some((format!("{}:{}", path, client_key), limit))
+    }
+
+    /// How long the client must wait before calling again, if it is over the limit.
+    /// Requests are let through when the store cannot be reached.
+    async fn check(&self, bucket: &str, limit: RateLimit) -> Option<Duration> {
+        match self.store.acquire(bucket, limit).await {
+            Ok(Decision::Allowed) => None,
+            Ok(Decision::Limited { retry_after }) => {
+                debug!("Rate limiting {}", bucket);
+                let route = bucket.split_once(':').map(|(route, _)| route).unwrap_or(bucket);
+                RATE_LIMITED_REQUESTS.with_label_values(&[route]).inc();
+                Some(retry_after)
+            }
+            Err(e) => {
+                warn!("Rate limit store unavailable, letting the request through: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+fn is_grpc(request: &Request) -> bool {
+    request.headers().get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with(GRPC_CONTENT_TYPE))
+}
+
+fn too_many_requests(retry_after: Duration, grpc: bool) -> Response {
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let message = format!("Too many requests, retry in {} seconds", seconds);
+
+    // A trailers-only gRPC response, HTTP errors would reach gRPC clients as UNAVAILABLE
+    if grpc {
+        let mut response = StatusCode::OK.into_response();
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(GRPC_CONTENT_TYPE));
+        headers.insert("grpc-status", HeaderValue::from_static(GRPC_RESOURCE_EXHAUSTED));
+        headers.insert("grpc-message", HeaderValue::from_str(&message).unwrap());
+        headers.insert(RETRY_AFTER, HeaderValue::from(seconds));
+        return response;
+    }
+
+    let body = json!({
+        "error": {
+            "code": "rate_limited",
+            "message": message,
+        }
+    });
+
+    (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, seconds.to_string())], Json(body)).into_response()
+}
+
+/// Answers 429 with `Retry-After` to clients going over the limit of the route they call,
+/// or `RESOURCE_EXHAUSTED` to gRPC clients, whose routes are the method paths.
+/// Routes without a limit are not counted.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self { limiter: Arc::new(limiter) }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService { inner, limiter: self.limiter.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // The ready service is the one that must handle the request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let bucket = limiter.bucket(&request);
+        let grpc = is_grpc(&request);
+
+        Box::pin(async move {
+            if let Some((bucket, limit)) = bucket {
+                if let Some(retry_after) = limiter.check(&bucket, limit).await {
+                    return Ok(too_many_requests(retry_after, grpc));
+                }
+            }
+
+            inner.call(request).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_limits_are_parsed() {
+        assert_eq!(
+            "/v2/rpc/click=2.5:10".parse(),
+            Ok(RouteRateLimit { path: "/v2/rpc/click".to_string(), limit: RateLimit { per_second: 2.5, burst: 10 } })
+        );
+        assert_eq!(
+            "/v2/rpc/click=2.5".parse::<RouteRateLimit>().map(|route| route.limit.burst),
+            Ok(3)
+        );
+        assert!("/v2/rpc/click".parse::<RouteRateLimit>().is_err());
+        assert!("v2/rpc/click=1:1".parse::<RouteRateLimit>().is_err());
+        assert!("/v2/rpc/click=0:1".parse::<RouteRateLimit>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bursts_then_waits_for_refill() {
+        let store = InMemoryRateLimitStore::new();
+        let limit = RateLimit { per_second: 10.0, burst: 3 };
+
+        for _ in 0..3 {
+            assert_eq!(store.acquire("client", limit).await.unwrap(), Decision::Allowed);
+        }
+
+        match store.acquire("client", limit).await.unwrap() {
+            Decision::Limited { retry_after } => assert!(retry_after <= Duration::from_millis(100)),
+            Decision::Allowed => panic!("the burst is exhausted"),
+        }
+
+        // Other clients have their own bucket
+        assert_eq!(store.acquire("other", limit).await.unwrap(), Decision::Allowed);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(store.acquire("client", limit).await.unwrap(), Decision::Allowed);
+    }
+
+    #[tokio::test]
+    async fn test_buckets_stay_bounded_when_none_is_full() {
+        let store = InMemoryRateLimitStore::new();
+        let limit = RateLimit { per_second: 0.001, burst: 1 };
+
+        for client in 0..=MAX_TRACKED_BUCKETS {
+            store.acquire(&client.to_string(), limit).await.unwrap();
+        }
+
+        let buckets = store.buckets.lock().unwrap();
+        assert!(buckets.len() <= TRACKED_BUCKETS_AFTER_EVICTION + 1);
+        // The least recently used ones went first
+        assert!(buckets.contains_key(&MAX_TRACKED_BUCKETS.to_string()));
+        assert!(!buckets.contains_key("0"));
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_FOR, "6.6.6.6, 1.2.3.4".parse().unwrap());
+
+        let direct = ClientIdentity::default();
+        assert_eq!(direct.client_key(&headers, Some(peer)), Some("ip:10.0.0.1".to_string()));
+
+        let behind_proxy = ClientIdentity { trusted_proxy_hops: 1, ..Default::default() };
+        assert_eq!(behind_proxy.client_key(&headers, Some(peer)), Some("ip:1.2.3.4".to_string()));
+
+        let behind_three_proxies = ClientIdentity { trusted_proxy_hops: 3, ..Default::default() };
+        assert_eq!(behind_three_proxies.client_key(&headers, Some(peer)), Some("ip:10.0.0.1".to_string()));
+
+        let with_sessions = ClientIdentity {
+            session_header: Some(HeaderName::from_static("x-session-token")),
+            ..behind_proxy
+        };
+        headers.insert("x-session-token", "secret".parse().unwrap());
+        let key = with_sessions.client_key(&headers, Some(peer)).unwrap();
+        assert!(key.starts_with("session:"));
+        assert!(!key.contains("secret"));
+    }
+
+    #[test]
+    fn test_limited_response() {
+        let response = too_many_requests(Duration::from_millis(1200), false);
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+
+        let grpc_response = too_many_requests(Duration::from_millis(1200), true);
+        assert_eq!(grpc_response.status(), StatusCode::OK);
+        assert_eq!(grpc_response.headers()["grpc-status"], GRPC_RESOURCE_EXHAUSTED);
+        assert_eq!(grpc_response.headers()[RETRY_AFTER], "2");
+    }
+}
@@ -6,7 +6,7 @@ use deadpool_redis::redis::AsyncCommands;
 use deadpool_redis::{redis, Config as RedisConfig, CreatePoolError, PoolError, Runtime};
 use log::error;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::in_memory_click_persistence::PapayaClickRepository;
@@ -15,6 +15,70 @@ use tracing::{debug, info, instrument, Span};
 
 const TILES_KEY: &str = "tiles";
 
+/// Last-writer-wins update of one tile, executed atomically by Redis, in the order of `supersedes`.
+///
+/// Members of the `tiles` sorted set are `country:timestamp_ns:node_id` scored by tile id,
+/// or `country:timestamp_ns` for those written before node ids, read as node 0.
+/// Timestamps are compared as decimal strings: nanosecond values do not fit in a Lua double.
+/// Returns the previous newest member (or nil) and 1 when the click was written, 0 otherwise.
+/// Any extra member left for the tile is removed so the set converges to one member per tile.
+const SAVE_CLICK_SCRIPT: &str = r#"
+local function order_of(member)
+    local ts, node = string.match(member, ":(%d+):(%d+)$")
+    if ts then
+        return ts, tonumber(node)
+    end
+    return string.match(member, ":(%d+)$"), 0
+end
+
+local function newer(ts_a, node_a, ts_b, node_b)
+    if ts_a ~= ts_b then
+        if #ts_a ~= #ts_b then
+            return #ts_a > #ts_b
+        end
+        return ts_a > ts_b
+    end
+    return node_a > node_b
+end
+
+local members = redis.call('ZRANGEBYSCORE', KEYS[1], ARGV[1], ARGV[1])
+local current = false
+local current_ts = nil
+local current_node = nil
+
+for _, member in ipairs(members) do
+    local ts, node = order_of(member)
+    if ts and (current_ts == nil or newer(ts, node, current_ts, current_node)) then
+        current = member
+        current_ts = ts
+        current_node = node
+    end
+end
+
+local applied = 0
+local keep = current
+
+if current_ts == nil or newer(ARGV[3], tonumber(ARGV[4]), current_ts, current_node) then
+    keep = ARGV[2] .. ':' .. ARGV[3] .. ':' .. ARGV[4]
+    applied = 1
+end
+
+for _, member in ipairs(members) do
+    if member ~= keep then
+        redis.call('ZREM', KEYS[1], member)
+    end
+end
+
+if applied == 1 then
+    redis.call('ZADD', KEYS[1], ARGV[1], keep)
+end
+
+return {current, applied}
+"#;
+
+/// Sent by its SHA-1 once Redis has cached it, instead of its whole source on every click
+static SAVE_CLICK: LazyLock<redis::Script> = LazyLock::new(|| redis::Script::new(SAVE_CLICK_SCRIPT));
+
 pub struct RedisClickRepository {
     redis_pool: Arc<deadpool_redis::Pool>,
 }
@@ -55,6 +119,30 @@ impl RedisClickRepository {
             redis_pool: Arc::new(redis_pool),
         })
     }
+
+    pub async fn ping(&self) -> Result<(), RedisError> {
+        let mut redis_conn = self.redis_pool.get().await?;
+        redis::cmd("PING").query_async::<_, String>(&mut redis_conn).await?;
+
+        Ok(())
+    }
+}
+
+/// Reads a member of the `tiles` set, see `SAVE_CLICK_SCRIPT`
+fn parse_ownership(tile_id: u32, value: &str) -> Option<Ownership> {
+    let (rest, last) = value.rsplit_once(':')?;
+
+    let (country_id, timestamp_ns, node_id) = match rest.rsplit_once(':') {
+        Some((country_id, timestamp_ns)) => (country_id, timestamp_ns, last.parse::<u32>().ok()?),
+        None => (rest, last, 0),
+    };
+
+    Some(Ownership {
+        tile_id,
+        country_id: country_id.to_string(),
+        timestamp_ns: timestamp_ns.parse::<u64>().ok()?,
+        node_id,
+    })
 }
 
 #[async_trait]
@@ -70,20 +158,7 @@ impl ClickRepository for RedisClickRepository {
             .await
             .map_err(RedisError::from)?;
 
-        if let Some(val) = tile_contents.first() {
-            let parts: Vec<&str> = val.split(':').collect();
-            if parts.len() == 2 {
-                if let Ok(_) = parts[1].parse::<u64>() {
-                    return Ok(Some(Ownership {
-                        tile_id: tile_id as u32,
-                        country_id: parts[0].to_string(),
-                        timestamp_ns: parts[1].parse::<u64>().unwrap(),
-                    }));
-                }
-            }
-        }
-
-        Ok(None)
+        Ok(tile_contents.first().and_then(|value| parse_ownership(tile_id, value)))
     }
 
     async fn get_ownerships(&self) -> Result<OwnershipState, ClickRepositoryError> {
@@ -102,19 +177,10 @@ impl ClickRepository for RedisClickRepository {
         let mut ownerships = Vec::new();
 
         for (contents, tile_id) in tile_contents {
-            let parts: Vec<&str> = contents.split(':').collect();
-            if parts.len() == 2 {
-                if let Ok(timestamp_ns) = parts[1].parse::<u64>() {
-                    let tile_id = tile_id.parse::<u32>()
-                        .map_err(|e| ClickRepositoryError::InvalidDataError(e.to_string()))?;
-
-                    ownerships.push(Ownership {
-                        tile_id,
-                        country_id: parts[0].to_string(),
-                        timestamp_ns,
-                    });
-                }
-            }
+            let tile_id = tile_id.parse::<u32>()
+                .map_err(|e| ClickRepositoryError::InvalidDataError(e.to_string()))?;
+
+            ownerships.extend(parse_ownership(tile_id, &contents));
         }
 
         Ok(OwnershipState { ownerships })
@@ -139,17 +205,10 @@ impl ClickRepository for RedisClickRepository {
         let mut ownerships = Vec::new();
 
         for (contents, tile_id) in tile_contents {
-            let parts: Vec<&str> = contents.split(':').collect();
-            if parts.len() == 2 {
-                if let Ok(timestamp_ns) = parts[1].parse::<u64>() {
-                    ownerships.push(Ownership {
-                        tile_id: tile_id.parse::<u32>()
-                            .map_err(|e| ClickRepositoryError::InvalidDataError(e.to_string()))?,
-                        country_id: parts[0].to_string(),
-                        timestamp_ns: timestamp_ns,
-                    });
-                }
-            }
+            let tile_id = tile_id.parse::<u32>()
+                .map_err(|e| ClickRepositoryError::InvalidDataError(e.to_string()))?;
+
+            ownerships.extend(parse_ownership(tile_id, &contents));
         }
 
         Ok(OwnershipState { ownerships })
@@ -179,77 +238,41 @@ impl ClickRepository for RedisClickRepository {
 
         let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
 
-        let current_value: Option<String> = redis_conn
-            .zrangebyscore::<String, u32, u32, Vec<String>>(TILES_KEY.to_string(), tile_id, tile_id)
+        // The comparison and the write happen in one script so concurrent persisters cannot
+        // interleave between reading the current owner and replacing it.
+        let (current_value, applied): (Option<String>, bool) = SAVE_CLICK
+            .key(TILES_KEY)
+            .arg(tile_id)
+            .arg(&click.country_id)
+            .arg(click.timestamp_ns)
+            .arg(click.node_id)
+            .invoke_async(&mut redis_conn)
             .await
-            .map_err(RedisError::from)?
-            .get(0)
-            .cloned();
+            .map_err(RedisError::from)?;
 
         debug!(
-           "Current value for tile {} ({:?})",
+           "Previous value for tile {} ({:?})",
            tile_id, current_value
         );
 
-        // Create previous ownership object
-        let previous_ownership: Option<Ownership> = if let Some(current_val) = &current_value {
-            let parts: Vec<&str> = current_val.split(':').collect();
-            if parts.len() == 2 {
-                if let Ok(current_ts) = parts[1].parse::<u64>() {
-                    let ownership = Some(Ownership {
-                        tile_id,
-                        country_id: parts[0].to_string(),
-                        timestamp_ns: current_ts,
-                    });
-
-                    if click.timestamp_ns <= current_ts {
-                        info!(
-                            "Ignoring outdated update for tile {} (current: {}, received: {})",
-                            tile_id, current_ts, click.timestamp_ns
-                        );
-
-                        return Ok(ownership);
-                    }
-
-                    ownership
-                } else {
-                    None
-                }
-            } else {
-                return Err(ClickRepositoryError::InvalidDataError(current_val.clone()));
+        let previous_ownership: Option<Ownership> = match &current_value {
+            Some(current_val) => Some(parse_ownership(tile_id, current_val)
+                .ok_or_else(|| ClickRepositoryError::InvalidDataError(current_val.clone()))?),
+            None => {
+                debug!("No key for tile {}", tile_id);
+                None
             }
-        } else {
-            debug!("No key for tile {}", tile_id);
-            None
         };
 
-        let new_value = format!("{}:{}", click.country_id, click.timestamp_ns);
-
-        debug!(
-           "New value for tile {} ({:?})",
-           tile_id, new_value
-        );
-
-        let old_values: Vec<String> = redis_conn
-            .zrangebyscore(TILES_KEY, tile_id as f64, tile_id as f64)
-            .await
-            .map_err(RedisError::from)?;
+        if !applied {
+            info!(
+                "Ignoring outdated update for tile {} (current: {:?}, received: {})",
+                tile_id, previous_ownership.as_ref().map(|o| o.timestamp_ns), click.timestamp_ns
+            );
 
-        let mut pipe = redis::pipe();
-        let mut pipe = pipe
-            .atomic()
-            .zadd(TILES_KEY, new_value.clone(), tile_id as f64);
-
-        for old_value in old_values {
-            if old_value != new_value {
-                pipe = pipe.zrem(TILES_KEY, old_value);
-            }
+            return Ok(previous_ownership);
         }
 
-        pipe.query_async(&mut redis_conn)
-            .await
-            .map_err(RedisError::from)?;
-
         let processing_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -269,6 +292,47 @@ impl ClickRepository for RedisClickRepository {
 
         Ok(previous_ownership)
     }
+
+    /// Runs the script of every click in a single pipelined round trip, each run stays atomic.
+    /// When Redis does not have the script cached yet it is loaded and the batch is sent again,
+    /// which is harmless since clicks older than the stored owner are not written.
+    async fn save_clicks(&self, clicks: &[(u32, Click)]) -> Result<usize, ClickRepositoryError> {
+        if clicks.is_empty() {
+            return Ok(0);
+        }
+
+        let mut pipeline = redis::pipe();
+        for (tile_id, click) in clicks {
+            pipeline.cmd("EVALSHA")
+                .arg(SAVE_CLICK.get_hash())
+                .arg(1)
+                .arg(TILES_KEY)
+                .arg(*tile_id)
+                .arg(&click.country_id)
+                .arg(click.timestamp_ns)
+                .arg(click.node_id);
+        }
+
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+        let results: Vec<(Option<String>, bool)> = match pipeline.query_async(&mut redis_conn).await {
+            Err(e) if e.kind() == redis::ErrorKind::NoScriptError => {
+                redis::cmd("SCRIPT")
+                    .arg("LOAD")
+                    .arg(SAVE_CLICK_SCRIPT)
+                    .query_async::<_, String>(&mut redis_conn)
+                    .await
+                    .map_err(RedisError::from)?;
+                pipeline.query_async(&mut redis_conn).await
+            }
+            results => results,
+        }
+            .map_err(RedisError::from)?;
+
+        let applied = results.iter().filter(|(_, applied)| *applied).count();
+        debug!("Saved {} clicks, {} were newer than the stored owner", clicks.len(), applied);
+
+        Ok(applied)
+    }
 }
 
 
@@ -304,6 +368,7 @@ mod click_tests {
             tile_id: tile_id as i32,
             country_id: country_id.to_string(),
             timestamp_ns: now,
+            node_id: 0,
             click_id: format!("test_click_{}", tile_id),
         }
     }
@@ -417,6 +482,114 @@ mod click_tests {
         assert!(ownership.is_some());
     }
 
+    #[tokio::test]
+    async fn test_outdated_click_is_ignored() {
+        let (repo, _container) = create_test_repo().await;
+
+        let mut newer = create_test_click(1, "country1");
+        newer.timestamp_ns = 2_000_000_000_000_000_000;
+        let mut older = create_test_click(1, "country2");
+        older.timestamp_ns = 1_999_999_999_999_999_999;
+
+        repo.save_click(1, &newer).await.unwrap();
+        let previous = repo.save_click(1, &older).await.unwrap().unwrap();
+        assert_eq!(previous.country_id, "country1");
+        assert_eq!(previous.timestamp_ns, newer.timestamp_ns);
+
+        let current = repo.get_tile(1).await.unwrap().unwrap();
+        assert_eq!(current.country_id, "country1");
+        assert_eq!(repo.get_ownerships_by_batch(1, 1).await.unwrap().ownerships.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_equal_timestamps_are_ordered_by_node() {
+        let (repo, _container) = create_test_repo().await;
+
+        let mut legacy = create_test_click(1, "country1");
+        legacy.timestamp_ns = 1_000;
+        let mut higher_node = create_test_click(1, "country2");
+        higher_node.timestamp_ns = 1_000;
+        higher_node.node_id = 7;
+        let mut lower_node = create_test_click(1, "country3");
+        lower_node.timestamp_ns = 1_000;
+        lower_node.node_id = 3;
+
+        repo.save_click(1, &legacy).await.unwrap();
+        repo.save_click(1, &higher_node).await.unwrap();
+        let previous = repo.save_click(1, &lower_node).await.unwrap().unwrap();
+        assert_eq!((previous.country_id.as_str(), previous.node_id), ("country2", 7));
+
+        let current = repo.get_tile(1).await.unwrap().unwrap();
+        assert_eq!((current.country_id.as_str(), current.node_id), ("country2", 7));
+    }
+
+    #[tokio::test]
+    async fn test_batch_is_saved_in_one_call() {
+        let (repo, _container) = create_test_repo().await;
+
+        let mut stored = create_test_click(1, "country1");
+        stored.timestamp_ns = 2_000;
+        repo.save_click(1, &stored).await.unwrap();
+
+        let mut outdated = create_test_click(1, "country2");
+        outdated.timestamp_ns = 1_000;
+        let mut fresh = create_test_click(2, "country3");
+        fresh.timestamp_ns = 3_000;
+
+        let applied = repo.save_clicks(&[(1, outdated), (2, fresh)]).await.unwrap();
+        assert_eq!(applied, 1);
+        assert_eq!(repo.get_tile(1).await.unwrap().unwrap().country_id, "country1");
+        assert_eq!(repo.get_tile(2).await.unwrap().unwrap().country_id, "country3");
+    }
+
+    #[test]
+    fn test_members_without_node_id_are_read_as_node_0() {
+        let legacy = parse_ownership(1, "fr:1000").unwrap();
+        assert_eq!((legacy.country_id.as_str(), legacy.timestamp_ns, legacy.node_id), ("fr", 1000, 0));
+
+        let current = parse_ownership(1, "fr:1000:7").unwrap();
+        assert_eq!((current.country_id.as_str(), current.timestamp_ns, current.node_id), ("fr", 1000, 7));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_clicks_converge_to_newest() {
+        let (repo, _container) = create_test_repo().await;
+        let repo = Arc::new(repo);
+        let base_timestamp: u64 = 1_700_000_000_000_000_000;
+
+        // Timestamps only differ in their last digits, below the precision of a double
+        let handles: Vec<_> = (0..64u64)
+            .rev()
+            .map(|i| {
+                let repo = repo.clone();
+                tokio::spawn(async move {
+                    for tile_id in 1..=4 {
+                        let click = Click {
+                            tile_id: tile_id as i32,
+                            country_id: format!("country{}", i),
+                            timestamp_ns: base_timestamp + i,
+                            node_id: 0,
+                            click_id: format!("click_{}_{}", tile_id, i),
+                        };
+                        repo.save_click(tile_id, &click).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let state = repo.get_ownerships_by_batch(1, 4).await.unwrap();
+        assert_eq!(state.ownerships.len(), 4);
+
+        for ownership in state.ownerships {
+            assert_eq!(ownership.country_id, "country63");
+            assert_eq!(ownership.timestamp_ns, base_timestamp + 63);
+        }
+    }
+
     #[tokio::test]
     async fn test_error_handling() {
         let (repo, container) = create_test_repo().await;
@@ -0,0 +1,154 @@
+use std::convert::Infallible;
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use prost::Message;
+use serde::Deserialize;
+use serde_json::json;
+
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// Historical JSON wrapper of the RPC requests: the protobuf bytes as an array of numbers.
+#[derive(Debug, Deserialize)]
+struct JsonPayload {
+    data: Vec<u8>,
+}
+
+/// Protobuf request body, sent either raw with `Content-Type: application/x-protobuf` or wrapped in JSON.
+pub struct ProtoRequest<M>(pub M);
+
+#[async_trait]
+impl<S, M> FromRequest<S> for ProtoRequest<M>
+where
+    S: Send + Sync,
+    M: Message + Default,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let bytes = if has_protobuf_content_type(req.headers()) {
+            Bytes::from_request(req, state).await
+                .map_err(|rejection| rejection.status())?
+        } else {
+            let Json(payload) = Json::<JsonPayload>::from_request(req, state).await
+                .map_err(|rejection| rejection.status())?;
+            Bytes::from(payload.data)
+        };
+
+        M::decode(bytes)
+            .map(ProtoRequest)
+            .map_err(|_| StatusCode::BAD_REQUEST)
+    }
+}
+
+/// Encoding of the RPC responses, raw protobuf when the caller accepts `application/x-protobuf`,
+/// otherwise JSON with the base64 encoded protobuf in `data`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RpcFormat {
+    Json,
+    Protobuf,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RpcFormat {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let accepts_protobuf = parts.headers.get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media_type| media_type.split(';').next().unwrap_or("").trim() == PROTOBUF_CONTENT_TYPE);
+
+        Ok(if accepts_protobuf { RpcFormat::Protobuf } else { RpcFormat::Json })
+    }
+}
+
+impl RpcFormat {
+    pub fn respond<M: Message>(self, message: &M) -> Response {
+        ([(CONTENT_TYPE, self.content_type())], self.encode(message)).into_response()
+    }
+
+    /// Response body of the message, for callers building the response themselves
+    pub fn encode<M: Message>(self, message: &M) -> Bytes {
+        let bytes = message.encode_to_vec();
+
+        match self {
+            RpcFormat::Protobuf => Bytes::from(bytes),
+            RpcFormat::Json => Bytes::from(json!({
+                "data": STANDARD.encode(&bytes),
+            }).to_string()),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            RpcFormat::Protobuf => PROTOBUF_CONTENT_TYPE,
+            RpcFormat::Json => "application/json",
+        }
+    }
+}
+
+fn has_protobuf_content_type(headers: &HeaderMap) -> bool {
+    headers.get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or("").trim() == PROTOBUF_CONTENT_TYPE)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use clickplanet_proto::clicks::BatchRequest;
+
+    fn batch_request() -> BatchRequest {
+        BatchRequest {
+            start_tile_id: 1,
+            end_tile_id: 100,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_decoded_from_both_encodings() {
+        let protobuf = Request::builder()
+            .header(CONTENT_TYPE, PROTOBUF_CONTENT_TYPE)
+            .body(Body::from(batch_request().encode_to_vec()))
+            .unwrap();
+
+        let wrapped = Request::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "data": batch_request().encode_to_vec() }).to_string()))
+            .unwrap();
+
+        let ProtoRequest(decoded) = ProtoRequest::<BatchRequest>::from_request(protobuf, &()).await.unwrap();
+        assert_eq!(decoded, batch_request());
+
+        let ProtoRequest(decoded) = ProtoRequest::<BatchRequest>::from_request(wrapped, &()).await.unwrap();
+        assert_eq!(decoded, batch_request());
+    }
+
+    #[tokio::test]
+    async fn test_response_format_follows_accept() {
+        let (mut parts, _) = Request::builder()
+            .header(ACCEPT, PROTOBUF_CONTENT_TYPE)
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let format = RpcFormat::from_request_parts(&mut parts, &()).await.unwrap();
+        assert_eq!(format, RpcFormat::Protobuf);
+
+        let response = format.respond(&batch_request());
+        assert_eq!(response.headers()[CONTENT_TYPE], PROTOBUF_CONTENT_TYPE);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(BatchRequest::decode(body).unwrap(), batch_request());
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use clickplanet_proto::clicks::UpdateNotification;
+use tokio::sync::Notify;
+use tokio::time::{timeout_at, Instant};
+
+/// When a listener that cannot keep up gets a resync, and when it gets disconnected.
+#[derive(Clone, Copy, Debug)]
+pub struct SlowConsumerPolicy {
+    /// Distinct tiles waiting to be sent before the backlog is dropped in favor of a resync
+    pub max_pending_updates: usize,
+    /// Resyncs tolerated on one connection before closing it
+    pub max_lag_events: u32,
+}
+
+impl Default for SlowConsumerPolicy {
+    fn default() -> Self {
+        Self {
+            max_pending_updates: 10000,
+            max_lag_events: 3,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outgoing {
+    Updates(Vec<UpdateNotification>),
+    Resync,
+    Close,
+}
+
+/// Notifications waiting to be written to one listener, keeping only the latest change of each tile.
+pub struct UpdateQueue {
+    policy: SlowConsumerPolicy,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    order: VecDeque<i32>,
+    latest: HashMap<i32, UpdateNotification>,
+    resync: bool,
+    lag_events: u32,
+    missed_updates: u64,
+}
+
+impl UpdateQueue {
+    pub fn new(policy: SlowConsumerPolicy) -> Self {
+        Self {
+            policy,
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn push(&self, notification: UpdateNotification) {
+        let mut state = self.state.lock().unwrap();
+
+        let tile_id = notification.tile_id;
+        if state.latest.insert(tile_id, notification).is_none() {
+            state.order.push_back(tile_id);
+        }
+
+        if state.latest.len() > self.policy.max_pending_updates {
+            let missed = state.latest.len() as u64;
+            Self::drop_backlog(&mut state, missed);
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// Updates were lost before reaching the queue, the listener has to resync.
+    pub fn lagged(&self, missed_updates: u64) {
+        let mut state = self.state.lock().unwrap();
+        Self::drop_backlog(&mut state, missed_updates);
+
+        self.notify.notify_one();
+    }
+
+    fn drop_backlog(state: &mut QueueState, missed_updates: u64) {
+        state.order.clear();
+        state.latest.clear();
+        state.resync = true;
+        state.lag_events += 1;
+        state.missed_updates += missed_updates;
+    }
+
+    /// (lag events, missed updates) since the connection was opened
+    pub fn lag(&self) -> (u32, u64) {
+        let state = self.state.lock().unwrap();
+        (state.lag_events, state.missed_updates)
+    }
+
+    pub async fn next(&self) -> Outgoing {
+        loop {
+            if let Some(outgoing) = self.take(usize::MAX) {
+                return outgoing;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    /// Like `next`, but once updates are pending keeps gathering them for up to `window`,
+    /// or until `max_updates` of them are pending.
+    pub async fn next_batch(&self, max_updates: usize, window: Duration) -> Outgoing {
+        while !self.has_pending() {
+            self.notify.notified().await;
+        }
+
+        let deadline = Instant::now() + window;
+        while !self.is_full(max_updates) {
+            if timeout_at(deadline, self.notify.notified()).await.is_err() {
+                break;
+            }
+        }
+
+        match self.take(max_updates) {
+            Some(outgoing) => outgoing,
+            None => self.next().await,
+        }
+    }
+
+    fn has_pending(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.resync || !state.order.is_empty() || state.lag_events > self.policy.max_lag_events
+    }
+
+    fn is_full(&self, max_updates: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        state.resync || state.order.len() >= max_updates || state.lag_events > self.policy.max_lag_events
+    }
+
+    fn take(&self, max_updates: usize) -> Option<Outgoing> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.lag_events > self.policy.max_lag_events {
+            return Some(Outgoing::Close);
+        }
+
+        if state.resync {
+            state.resync = false;
+            return Some(Outgoing::Resync);
+        }
+
+        if state.order.is_empty() {
+            return None;
+        }
+
+        let QueueState { order, latest, .. } = &mut *state;
+        let count = order.len().min(max_updates);
+        let updates = order.drain(..count)
+            .filter_map(|tile_id| latest.remove(&tile_id))
+            .collect();
+
+        Some(Outgoing::Updates(updates))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn notification(tile_id: i32, country_id: &str, sequence: u64) -> UpdateNotification {
+        UpdateNotification {
+            tile_id,
+            country_id: country_id.to_string(),
+            sequence,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pending_updates_are_coalesced_per_tile() {
+        let queue = UpdateQueue::new(SlowConsumerPolicy::default());
+
+        queue.push(notification(1, "fr", 1));
+        queue.push(notification(2, "fr", 2));
+        queue.push(notification(1, "ru", 3));
+
+        let expected = vec![notification(1, "ru", 3), notification(2, "fr", 2)];
+        assert_eq!(queue.next().await, Outgoing::Updates(expected));
+    }
+
+    #[tokio::test]
+    async fn test_backlog_overflow_resyncs_then_closes() {
+        let queue = UpdateQueue::new(SlowConsumerPolicy {
+            max_pending_updates: 2,
+            max_lag_events: 1,
+        });
+
+        for tile_id in 0..3 {
+            queue.push(notification(tile_id, "fr", tile_id as u64));
+        }
+        queue.push(notification(10, "fr", 10));
+
+        assert_eq!(queue.next().await, Outgoing::Resync);
+        assert_eq!(queue.next().await, Outgoing::Updates(vec![notification(10, "fr", 10)]));
+        assert_eq!(queue.lag(), (1, 3));
+
+        queue.lagged(50);
+        assert_eq!(queue.next().await, Outgoing::Close);
+        assert_eq!(queue.lag(), (2, 53));
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_flushed_when_full_or_after_the_window() {
+        let queue = Arc::new(UpdateQueue::new(SlowConsumerPolicy::default()));
+
+        for tile_id in 0..5 {
+            queue.push(notification(tile_id, "fr", tile_id as u64));
+        }
+
+        // Full batch: no need to wait for the window
+        match queue.next_batch(3, Duration::from_secs(3600)).await {
+            Outgoing::Updates(updates) => assert_eq!(updates.len(), 3),
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        let producer = {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                queue.push(notification(10, "fr", 10));
+            })
+        };
+
+        // Filled by the producer before the window ends
+        match queue.next_batch(3, Duration::from_secs(3600)).await {
+            Outgoing::Updates(updates) => assert_eq!(updates.len(), 3),
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        producer.await.unwrap();
+        queue.push(notification(11, "fr", 11));
+
+        let started = Instant::now();
+        assert_eq!(queue.next_batch(3, Duration::from_millis(50)).await, Outgoing::Updates(vec![notification(11, "fr", 11)]));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
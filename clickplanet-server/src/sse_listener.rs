@@ -0,0 +1,145 @@
+use std::convert::Infallible;
+use std::time::Duration;
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use futures::{stream, Stream, StreamExt};
+use prost::Message;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::debug;
+use clickplanet_proto::clicks::UpdateNotification;
+
+use crate::click_persistence::ClickRepository;
+use crate::notification_log::{Replay, BROADCAST_DROPPED};
+use crate::AppState;
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SseFormat {
+    /// The notification fields as a JSON object
+    #[default]
+    Json,
+    /// The base64 encoded `UpdateNotification`
+    Protobuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SseParams {
+    #[serde(default)]
+    format: SseFormat,
+}
+
+/// Event ids are `<epoch>:<sequence>`, so `Last-Event-ID` tells where a reconnecting listener stopped.
+fn event_id(notification: &UpdateNotification) -> String {
+    format!("{}:{}", notification.epoch, notification.sequence)
+}
+
+fn parse_event_id(event_id: &str) -> Option<(u32, u64)> {
+    let (epoch, sequence) = event_id.split_once(':')?;
+    Some((epoch.parse().ok()?, sequence.parse().ok()?))
+}
+
+fn update_event(notification: &UpdateNotification, format: SseFormat) -> Event {
+    let data = match format {
+        SseFormat::Json => json!({
+            "tile_id": notification.tile_id,
+            "country_id": notification.country_id,
+            "previous_country_id": notification.previous_country_id,
+            "epoch": notification.epoch,
+            "sequence": notification.sequence,
+        }).to_string(),
+        SseFormat::Protobuf => STANDARD.encode(notification.encode_to_vec()),
+    };
+
+    Event::default()
+        .id(event_id(notification))
+        .event("update")
+        .data(data)
+}
+
+/// Missed notifications cannot be replayed: reload the ownerships before relying on the stream
+fn resync_event() -> Event {
+    Event::default().event("resync").data("{}")
+}
+
+pub async fn handle_sse_listen<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Query(params): Query<SseParams>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let format = params.format;
+
+    // Subscribe before reading the log so nothing falls between the replay and the live stream
+    let receiver = state.notification_log.subscribe();
+
+    let last_event_id = headers.get("last-event-id")
+        .and_then(|value| value.to_str().ok());
+
+    let replay = match last_event_id {
+        Some(last_event_id) => match parse_event_id(last_event_id) {
+            Some((epoch, sequence)) => state.notification_log.replay_after(epoch, sequence),
+            None => Replay::Resync,
+        },
+        None => Replay::Updates(Vec::new()),
+    };
+
+    let (initial, replayed_up_to) = match replay {
+        Replay::Updates(missed) => {
+            debug!("Replaying {} notifications", missed.len());
+            let replayed_up_to = missed.last().map(|notification| notification.sequence).unwrap_or(0);
+            (missed.iter().map(|notification| update_event(notification, format)).collect(), replayed_up_to)
+        }
+        Replay::Resync => (vec![resync_event()], 0),
+    };
+
+    let live = stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(notification) if notification.sequence <= replayed_up_to => continue,
+                Ok(notification) => return Some((update_event(&notification, format), receiver)),
+                Err(RecvError::Lagged(missed_updates)) => {
+                    debug!("SSE listener lagged behind by {} notifications", missed_updates);
+                    BROADCAST_DROPPED.with_label_values(&["updates", "sse"]).inc_by(missed_updates);
+                    return Some((resync_event(), receiver));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    // Ends the response on shutdown so the server can drain, clients reconnect with Last-Event-ID
+    let shutdown = state.shutdown.clone();
+    let events = stream::iter(initial).chain(live)
+        .take_until(async move { shutdown.wait().await })
+        .map(Ok);
+
+    Sse::new(events).keep_alive(
+        KeepAlive::new()
+            .interval(KEEP_ALIVE_INTERVAL)
+            .text("keep-alive")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_ids_roundtrip() {
+        let notification = UpdateNotification {
+            tile_id: 42,
+            epoch: 7,
+            sequence: 1234,
+            ..Default::default()
+        };
+
+        assert_eq!(parse_event_id(&event_id(&notification)), Some((7, 1234)));
+        assert_eq!(parse_event_id("1234"), None);
+        assert_eq!(parse_event_id("a:b"), None);
+    }
+}
@@ -1,6 +1,11 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
+use axum::routing::get;
+use axum::Router;
 use clap::Parser;
-use tracing::info;
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
 use crate::redis_click_persistence::{RedisClickRepository};
 
 mod jetstream_click_streamer;
@@ -9,9 +14,15 @@ mod telemetry;
 mod redis_click_persistence;
 mod click_persistence;
 mod in_memory_click_persistence;
+mod metrics;
+mod health;
+mod shutdown;
 
 use crate::nats_commons::ConsumerConfig;
-use crate::jetstream_click_streamer::{ClickConsumer};
+use crate::jetstream_click_streamer::{BatchConfig, ClickConsumer};
+use crate::health::{health_routes, ConditionCheck, NatsCheck, Readiness, RedisCheck};
+use crate::metrics::metrics_response;
+use crate::shutdown::Shutdown;
 use crate::telemetry::{init_telemetry, TelemetryConfig};
 
 #[derive(Parser, Debug)]
@@ -34,6 +45,22 @@ struct Args {
 
     #[arg(long, env = "ACK_WAIT_SECS", default_value = "10")]
     ack_wait_secs: u64,
+
+    /// Most clicks written to Redis in one round trip, only the newest of each tile is written
+    #[arg(long, env = "BATCH_SIZE", default_value = "500")]
+    batch_size: usize,
+
+    /// Longest wait for a batch to fill before it is written anyway
+    #[arg(long, env = "FLUSH_INTERVAL_MS", default_value = "50")]
+    flush_interval_ms: u64,
+
+    /// How long the saves and acknowledgments in flight get to complete after SIGTERM
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS", default_value = "20")]
+    shutdown_timeout_secs: u64,
+
+    /// Port of `/metrics`, `/healthz` and `/readyz`
+    #[arg(long, env = "ADMIN_PORT", default_value = "9100")]
+    admin_port: u16,
 }
 
 #[tokio::main]
@@ -45,24 +72,77 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         service_name: args.service_name,
     };
 
-    init_telemetry(telemetry_config).await?;
+    let telemetry = init_telemetry(telemetry_config).await?;
 
-    let click_persister = RedisClickRepository::new(&args.redis_url).await?;
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_signal();
 
-    let consumer = ClickConsumer::new(
+    let click_persister = Arc::new(RedisClickRepository::new(&args.redis_url).await?);
+
+    let consumer = Arc::new(ClickConsumer::new(
         &args.nats_url,
         Some(ConsumerConfig {
             concurrent_processors: args.concurrent_processors as usize,
             ack_wait: Duration::from_secs(args.ack_wait_secs),
             ..Default::default()
         }),
-        click_persister
+        BatchConfig {
+            size: args.batch_size.max(1),
+            flush_interval: Duration::from_millis(args.flush_interval_ms),
+        },
+        click_persister.clone()
     )
-        .await?;
+        .await?);
+
+    let readiness = Readiness::new()
+        .with(NatsCheck(consumer.jetstream()))
+        .with(RedisCheck(click_persister))
+        .with(ConditionCheck::new("click_consumer", {
+            let consumer = consumer.clone();
+            move || consumer.is_running()
+        }, "the click consumer is not running"));
+
+    let admin_listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], args.admin_port))).await?;
+    println!("Admin endpoints listening on 0.0.0.0:{}", args.admin_port);
+    let admin_server = axum::serve(admin_listener, Router::new()
+        .route("/metrics", get(|| async { metrics_response() }))
+        .merge(health_routes(readiness)));
 
     info!("Starting click consumer...");
-    consumer.run().await?;
+    let consumer_run = consumer.run(shutdown.clone());
+    tokio::pin!(consumer_run);
+
+    let result: Result<(), Box<dyn std::error::Error>> = tokio::select! {
+        result = &mut consumer_run => {
+            if !shutdown.is_triggered() {
+                error!("Unexpected click consumer exit");
+            }
+            result.map_err(Into::into)
+        }
+        result = admin_server => {
+            match result {
+                Err(e) => {
+                    error!("Admin server error: {:?}", e);
+                    Err(e.into())
+                }
+                Ok(()) => Ok(()),
+            }
+        }
+        _ = shutdown.wait() => {
+            // The admin endpoints stay up while the clicks in flight are persisted
+            match tokio::time::timeout(Duration::from_secs(args.shutdown_timeout_secs), &mut consumer_run).await {
+                Ok(result) => result.map_err(Into::into),
+                Err(_) => {
+                    warn!("Shutdown deadline reached, unacknowledged clicks will be redelivered");
+                    Ok(())
+                }
+            }
+        }
+    };
+
+    info!("Click persister stopped");
+    telemetry.shutdown().await;
 
-    Ok(())
+    result
 }
 
@@ -0,0 +1,3 @@
+pub const CLICK_SUBJECT_PREFIX: &str = "clicks.tile.";
+pub const CLICK_STREAM_NAME: &str = "CLICKS";
+pub const PERSISTER_CONSUMER_NAME: &str = "tile-state-processor";
@@ -0,0 +1,118 @@
+use std::collections::HashSet;
+use clickplanet_proto::clicks::UpdateNotification;
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CountryMatch {
+    Owner,
+    Previous,
+    #[default]
+    Either,
+}
+
+/// Which ownership changes a listener wants. Tile criteria and country criteria are combined,
+/// an empty criterion matches everything.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SubscriptionFilter {
+    /// Inclusive `[start, end]` tile id ranges
+    pub tile_ranges: Vec<(u32, u32)>,
+    pub tile_ids: HashSet<u32>,
+    pub countries: HashSet<String>,
+    pub country_match: CountryMatch,
+}
+
+impl SubscriptionFilter {
+    pub fn matches(&self, notification: &UpdateNotification) -> bool {
+        self.matches_tile(notification.tile_id) && self.matches_country(notification)
+    }
+
+    fn matches_tile(&self, tile_id: i32) -> bool {
+        if self.tile_ranges.is_empty() && self.tile_ids.is_empty() {
+            return true;
+        }
+
+        let Ok(tile_id) = u32::try_from(tile_id) else {
+            return false;
+        };
+
+        self.tile_ids.contains(&tile_id) ||
+            self.tile_ranges.iter().any(|(start, end)| (*start..=*end).contains(&tile_id))
+    }
+
+    fn matches_country(&self, notification: &UpdateNotification) -> bool {
+        if self.countries.is_empty() {
+            return true;
+        }
+
+        let owner = self.countries.contains(&notification.country_id);
+        let previous = self.countries.contains(&notification.previous_country_id);
+
+        match self.country_match {
+            CountryMatch::Owner => owner,
+            CountryMatch::Previous => previous,
+            CountryMatch::Either => owner || previous,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(tile_id: i32, country_id: &str, previous_country_id: &str) -> UpdateNotification {
+        UpdateNotification {
+            tile_id,
+            country_id: country_id.to_string(),
+            previous_country_id: previous_country_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_empty_filter_matches_everything() {
+        let filter = SubscriptionFilter::default();
+
+        assert!(filter.matches(&notification(42, "fr", "ru")));
+    }
+
+    #[test]
+    fn test_tile_criteria() {
+        let filter: SubscriptionFilter = serde_json::from_str(r#"{
+            "tile_ranges": [[10, 20]],
+            "tile_ids": [42]
+        }"#).unwrap();
+
+        assert!(filter.matches(&notification(10, "fr", "ru")));
+        assert!(filter.matches(&notification(20, "fr", "ru")));
+        assert!(filter.matches(&notification(42, "fr", "ru")));
+        assert!(!filter.matches(&notification(21, "fr", "ru")));
+        assert!(!filter.matches(&notification(-1, "fr", "ru")));
+    }
+
+    #[test]
+    fn test_country_criteria() {
+        let owner: SubscriptionFilter = serde_json::from_str(r#"{"countries": ["fr"], "country_match": "owner"}"#).unwrap();
+        let previous: SubscriptionFilter = serde_json::from_str(r#"{"countries": ["fr"], "country_match": "previous"}"#).unwrap();
+        let either: SubscriptionFilter = serde_json::from_str(r#"{"countries": ["fr"]}"#).unwrap();
+
+        let captured_by_fr = notification(1, "fr", "ru");
+        let lost_by_fr = notification(1, "ru", "fr");
+        let unrelated = notification(1, "ru", "es");
+
+        assert!(owner.matches(&captured_by_fr) && !owner.matches(&lost_by_fr));
+        assert!(!previous.matches(&captured_by_fr) && previous.matches(&lost_by_fr));
+        assert!(either.matches(&captured_by_fr) && either.matches(&lost_by_fr));
+        assert!(!either.matches(&unrelated));
+    }
+
+    #[test]
+    fn test_tile_and_country_criteria_are_combined() {
+        let filter: SubscriptionFilter = serde_json::from_str(r#"{"tile_ids": [1], "countries": ["fr"]}"#).unwrap();
+
+        assert!(filter.matches(&notification(1, "fr", "ru")));
+        assert!(!filter.matches(&notification(2, "fr", "ru")));
+        assert!(!filter.matches(&notification(1, "es", "ru")));
+    }
+}
@@ -1,4 +1,5 @@
 use opentelemetry::trace::{TracerProvider};
+use opentelemetry_sdk::trace::TracerProvider as SdkTracerProvider;
 use opentelemetry_otlp::WithExportConfig;
 use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Registry};
 use tracing_subscriber::fmt::Layer;
@@ -19,7 +20,24 @@ impl Default for TelemetryConfig {
     }
 }
 
-pub async fn init_telemetry(config: TelemetryConfig) -> Result<(), Box<dyn std::error::Error>> {
+/// Keeps the span exporter of the process, spans still buffered are lost unless it is shut down.
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Exports the buffered spans before the process exits
+    pub async fn shutdown(self) {
+        // The batch processor blocks until the exporter is done
+        let result = tokio::task::spawn_blocking(move || self.tracer_provider.shutdown()).await;
+
+        if let Ok(Err(e)) = result {
+            eprintln!("Could not flush telemetry: {:?}", e);
+        }
+    }
+}
+
+pub async fn init_telemetry(config: TelemetryConfig) -> Result<Telemetry, Box<dyn std::error::Error>> {
     let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder()
         .with_batch_exporter(
             opentelemetry_otlp::SpanExporter::builder()
@@ -58,7 +76,6 @@ pub async fn init_telemetry(config: TelemetryConfig) -> Result<(), Box<dyn std::
         .with(telemetry);
 
     tracing::subscriber::set_global_default(subscriber)?;
-    opentelemetry::global::shutdown_tracer_provider();
 
-    Ok(())
+    Ok(Telemetry { tracer_provider })
 }
@@ -0,0 +1,329 @@
+use std::borrow::Cow;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use axum::extract::ws::{close_code, CloseFrame, Message as WebsocketMessage, WebSocket};
+use axum::extract::{Query, State, WebSocketUpgrade};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use futures_util::{SinkExt, StreamExt};
+use prometheus::{register_int_gauge, IntGauge};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, info, warn};
+use clickplanet_proto::clicks::envelope::Payload;
+use clickplanet_proto::clicks::{Envelope, UpdateBatch, UpdateNotification};
+
+use crate::click_persistence::ClickRepository;
+use crate::notification_log::{Replay, BROADCAST_DROPPED};
+use crate::slow_consumer::{Outgoing, UpdateQueue};
+use crate::subscription_filter::SubscriptionFilter;
+use crate::AppState;
+
+/// Listeners asking for this subprotocol get `UpdateBatch` frames instead of one frame per `UpdateNotification`.
+pub const BATCH_SUBPROTOCOL: &str = "clickplanet.batch.v1";
+
+/// Listeners asking for this subprotocol get `Envelope` frames: update batches and leaderboard changes.
+pub const ENVELOPE_SUBPROTOCOL: &str = "clickplanet.envelope.v1";
+
+static WS_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "clickplanet_websocket_connections",
+    "Open websocket listener connections"
+).unwrap());
+
+/// How notifications are grouped for listeners using the batch subprotocol.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchConfig {
+    pub window: Duration,
+    pub max_updates: usize,
+}
+
+/// How the binary frames of a listener are encoded, depending on the negotiated subprotocol.
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    /// One `UpdateNotification` per frame
+    Single,
+    Batch(BatchConfig),
+    Envelope(BatchConfig),
+}
+
+impl Framing {
+    fn batching(&self) -> Option<BatchConfig> {
+        match self {
+            Framing::Single => None,
+            Framing::Batch(config) | Framing::Envelope(config) => Some(*config),
+        }
+    }
+}
+
+/// Position a reconnecting listener has already seen, taken from its last `UpdateNotification`,
+/// and the JSON of its first subscription, which then also filters the replay.
+#[derive(Debug, Deserialize)]
+pub struct ListenParams {
+    epoch: Option<u32>,
+    last_sequence: Option<u64>,
+    subscribe: Option<String>,
+}
+
+/// Out-of-band messages, sent as JSON text frames next to the binary notifications.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ControlMessage {
+    /// Missed notifications cannot be replayed: reload the ownerships before relying on the stream
+    Resync,
+}
+
+/// Messages listeners send as JSON text frames.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Only receive the matching ownership changes from now on, replaces any previous subscription.
+    /// Notifications already queued when it arrives may not be filtered.
+    Subscribe(SubscriptionFilter),
+}
+
+impl ControlMessage {
+    fn to_frame(&self) -> WebsocketMessage {
+        WebsocketMessage::Text(serde_json::to_string(self).unwrap())
+    }
+}
+
+pub async fn handle_ws_upgrade<T: ClickRepository + 'static>(
+    ws: WebSocketUpgrade,
+    Query(params): Query<ListenParams>,
+    State(state): State<AppState<T>>,
+) -> Response {
+    let filter = match params.subscribe.as_deref().map(serde_json::from_str::<SubscriptionFilter>) {
+        Some(Ok(filter)) => filter,
+        Some(Err(e)) => return (StatusCode::BAD_REQUEST, format!("Invalid subscribe parameter: {}", e)).into_response(),
+        None => SubscriptionFilter::default(),
+    };
+
+    // Tracked from the request on, so the shutdown waits for the close frame of the connection
+    let token = state.ws_tasks.token();
+
+    ws.protocols([ENVELOPE_SUBPROTOCOL, BATCH_SUBPROTOCOL])
+        .on_upgrade(move |socket| async move {
+            handle_ws_connection(socket, params, filter, state).await;
+            drop(token);
+        })
+}
+
+fn envelope_frame(payload: Payload) -> WebsocketMessage {
+    WebsocketMessage::Binary(Envelope { payload: Some(payload) }.encode_to_vec())
+}
+
+fn notification_frames(notifications: &[UpdateNotification], framing: Framing) -> Vec<WebsocketMessage> {
+    match framing {
+        Framing::Batch(config) => notifications.chunks(config.max_updates.max(1))
+            .map(|chunk| WebsocketMessage::Binary(UpdateBatch { updates: chunk.to_vec() }.encode_to_vec()))
+            .collect(),
+        Framing::Envelope(config) => notifications.chunks(config.max_updates.max(1))
+            .map(|chunk| envelope_frame(Payload::Updates(UpdateBatch { updates: chunk.to_vec() })))
+            .collect(),
+        Framing::Single => notifications.iter()
+            .map(|notification| WebsocketMessage::Binary(notification.encode_to_vec()))
+            .collect(),
+    }
+}
+
+async fn handle_ws_connection<T: ClickRepository>(socket: WebSocket, params: ListenParams, filter: SubscriptionFilter, state: AppState<T>) {
+    let framing = match socket.protocol() {
+        Some(protocol) if protocol == ENVELOPE_SUBPROTOCOL => Framing::Envelope(state.update_batching),
+        Some(protocol) if protocol == BATCH_SUBPROTOCOL => Framing::Batch(state.update_batching),
+        _ => Framing::Single,
+    };
+    WS_CONNECTIONS.inc();
+    let (sender, mut receiver) = socket.split();
+    let sender_arc = Arc::new(Mutex::new(sender));
+
+    // Subscribe before reading the log so nothing falls between the replay and the live stream
+    let mut update_notification_subscription: Receiver<UpdateNotification> = state.notification_log.subscribe();
+    let sender_arc_clone = sender_arc.clone();
+    let closing_sender = sender_arc.clone();
+    let leaderboard_sender = sender_arc.clone();
+    let (filter_sender, filter_receiver) = watch::channel(filter);
+    let live_filter_receiver = filter_receiver.clone();
+
+    let replay = match (params.epoch, params.last_sequence) {
+        (Some(epoch), Some(last_sequence)) => Some(state.notification_log.replay_after(epoch, last_sequence)),
+        (None, Some(_)) => Some(Replay::Resync),
+        _ => None,
+    };
+
+    let replayed_up_to = match &replay {
+        Some(Replay::Updates(missed)) => missed.last().map(|notification| notification.sequence).unwrap_or(0),
+        _ => 0,
+    };
+
+    // Live notifications wait in a per-connection queue: changes of the same tile are coalesced
+    // while the socket is slow, and a listener falling too far behind gets a resync instead.
+    let queue = Arc::new(UpdateQueue::new(state.slow_consumer_policy));
+    let queue_clone = queue.clone();
+
+    let mut queue_task = tokio::spawn(async move {
+        loop {
+            match update_notification_subscription.recv().await {
+                Ok(notification) => {
+                    if notification.sequence <= replayed_up_to || !live_filter_receiver.borrow().matches(&notification) {
+                        continue;
+                    }
+
+                    queue_clone.push(notification);
+                }
+                Err(RecvError::Lagged(missed_updates)) => {
+                    debug!("Listener lagged behind by {} notifications", missed_updates);
+                    BROADCAST_DROPPED.with_label_values(&["updates", "websocket"]).inc_by(missed_updates);
+                    queue_clone.lagged(missed_updates);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let mut send_task = tokio::spawn(async move {
+        match replay {
+            Some(Replay::Updates(missed)) => {
+                debug!("Replaying {} notifications", missed.len());
+                let missed: Vec<UpdateNotification> = missed.into_iter()
+                    .filter(|notification| filter_receiver.borrow().matches(notification))
+                    .collect();
+
+                let mut sender = sender_arc.lock().await;
+                for frame in notification_frames(&missed, framing) {
+                    if let Err(e) = sender.send(frame).await {
+                        eprintln!("Error sending WebSocket message: {}", e);
+                        return;
+                    }
+                }
+            }
+            Some(Replay::Resync) => {
+                info!("Listener asked to resume from an unknown position, requesting a resync");
+                let mut sender = sender_arc.lock().await;
+                if let Err(e) = sender.send(ControlMessage::Resync.to_frame()).await {
+                    eprintln!("Error sending WebSocket message: {}", e);
+                    return;
+                }
+            }
+            None => {}
+        }
+
+        loop {
+            let outgoing = match framing.batching() {
+                Some(config) => queue.next_batch(config.max_updates, config.window).await,
+                None => queue.next().await,
+            };
+
+            let frames = match outgoing {
+                Outgoing::Updates(updates) => notification_frames(&updates, framing),
+                Outgoing::Resync => vec![ControlMessage::Resync.to_frame()],
+                Outgoing::Close => {
+                    let (lag_events, missed_updates) = queue.lag();
+                    warn!("Closing slow listener after {} lag events, {} notifications missed", lag_events, missed_updates);
+
+                    let mut sender = sender_arc.lock().await;
+                    let _ = sender.send(WebsocketMessage::Close(Some(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: Cow::from("slow consumer"),
+                    }))).await;
+                    return;
+                }
+            };
+
+            let mut sender = sender_arc.lock().await;
+            for frame in frames {
+                if let Err(e) = sender.send(frame).await {
+                    eprintln!("Error sending WebSocket message: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    // Every listener gets the scores computed once per tick: a snapshot first, then the deltas
+    // as long as none is skipped, a new snapshot otherwise
+    let mut leaderboard = state.leaderboard_feed.subscribe();
+    let leaderboard_task = tokio::spawn(async move {
+        if !matches!(framing, Framing::Envelope(_)) {
+            return;
+        }
+
+        let mut sent_version = 0;
+        loop {
+            let frame = match leaderboard.borrow_and_update().clone() {
+                Some(tick) if tick.version != sent_version => {
+                    let payload = if sent_version != 0 && tick.version == sent_version + 1 {
+                        Payload::LeaderboardDelta(tick.delta.clone())
+                    } else {
+                        Payload::LeaderboardSnapshot(tick.snapshot.clone())
+                    };
+                    sent_version = tick.version;
+                    Some(envelope_frame(payload))
+                }
+                _ => None,
+            };
+
+            if let Some(frame) = frame {
+                let mut sender = leaderboard_sender.lock().await;
+                if let Err(e) = sender.send(frame).await {
+                    eprintln!("Error sending WebSocket message: {}", e);
+                    return;
+                }
+            }
+
+            if leaderboard.changed().await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let mut recv_task = tokio::spawn(async move {
+        while let Some(Ok(message)) = receiver.next().await {
+            match message {
+                WebsocketMessage::Ping(payload) => {
+                    let mut sender = sender_arc_clone.lock().await;
+                    if let Err(e) = sender.send(WebsocketMessage::Pong(payload)).await {
+                        eprintln!("Error sending pong: {}", e);
+                        break;
+                    }
+                }
+                WebsocketMessage::Text(text) => {
+                    match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(ClientMessage::Subscribe(filter)) => {
+                            debug!("Listener subscribed to {:?}", filter);
+                            filter_sender.send_replace(filter);
+                        }
+                        Err(e) => warn!("Ignoring invalid listener message: {}", e),
+                    }
+                }
+                WebsocketMessage::Close(_) => break,
+                _ => {}
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = &mut send_task => {},
+        _ = &mut recv_task => {},
+        _ = &mut queue_task => {},
+        _ = state.shutdown.wait() => {
+            send_task.abort();
+            leaderboard_task.abort();
+            let _ = (&mut send_task).await;
+
+            let mut sender = closing_sender.lock().await;
+            let _ = sender.send(WebsocketMessage::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: Cow::from("server shutting down"),
+            }))).await;
+        },
+    }
+
+    send_task.abort();
+    recv_task.abort();
+    queue_task.abort();
+    leaderboard_task.abort();
+    WS_CONNECTIONS.dec();
+}